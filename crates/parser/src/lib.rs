@@ -1,39 +1,19 @@
 mod matrix;
+mod source;
 mod vial;
 
-use keys::keys::{Key, KeyIndex};
 pub use matrix::{Item as MatrixItem, Matrix, parse as parse_matix};
-use s_expression::Expr;
-use std::collections::HashMap;
+pub use source::{Position, Source, parse_keymap};
 pub use vial::{Item as VialItem, Vial, parse as parse_vial};
 
 #[derive(Debug, Default)]
 pub struct Keyboard {
     pub matrix: Matrix,
     pub vial: Vial,
-    pub source: HashMap<Key, KeyIndex>,
+    pub source: Source,
     pub meta: String,
 }
 
-pub fn parse_keymap(lst: &Vec<Expr>) -> Result<HashMap<Key, KeyIndex>, String> {
-    lst.iter()
-        .enumerate()
-        .try_fold(HashMap::with_capacity(lst.len()), |mut acc, (i, expr)| {
-            let key: Key = expr
-                .atom()?
-                .parse()
-                .map_err(|_| format!("Unknown key {}", expr))?;
-            if acc
-                .insert(key, i.try_into().map_err(|_| format!("Parse error"))?)
-                .is_some()
-            {
-                Err(format!("Key {:?} duplicate", key))
-            } else {
-                Ok(acc)
-            }
-        })
-}
-
 pub fn parse(keyboard: &str) -> Result<Keyboard, String> {
     let content = match keyboard {
         "imperial44" => include_str!("keyboards/imperial44.rkl"),
@@ -62,7 +42,7 @@ pub fn parse(keyboard: &str) -> Result<Keyboard, String> {
                 Ok(())
             }
             "defsrc" => {
-                keyboard.source = parse_keymap(&lst[1..].to_vec())?;
+                keyboard.source = parse_keymap(&lst[1..])?;
                 Ok(())
             }
             _ => Err(format!("Unexpected {}", fun)),
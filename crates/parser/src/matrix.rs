@@ -14,6 +14,21 @@ pub struct Item {
 #[derive(Debug, Default)]
 pub struct Matrix(Vec<Item>);
 
+impl Item {
+    pub fn key(&self) -> &Key {
+        &self.key
+    }
+    pub fn center_x(&self) -> f32 {
+        self.x + self.w / 2.0
+    }
+}
+
+impl Matrix {
+    pub fn items(&self) -> &[Item] {
+        &self.0
+    }
+}
+
 fn parse_item(row: &[&str]) -> Result<Item, String> {
     match row.len() {
         5 => {
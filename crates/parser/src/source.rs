@@ -0,0 +1,108 @@
+use keys::keys::{Key, KeyIndex};
+use s_expression::Expr::{self, *};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Position {
+    pub key: Key,
+    pub label: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Source(Vec<Position>);
+
+impl Source {
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = (KeyIndex, &Position)> {
+        self.0
+            .iter()
+            .enumerate()
+            .filter_map(|(i, p)| Some((i.try_into().ok()?, p)))
+    }
+    pub fn get(&self, index: &KeyIndex) -> Option<&Position> {
+        self.0.get(TryInto::<usize>::try_into(index).ok()?)
+    }
+    pub fn contains_key(&self, key: &Key) -> bool {
+        self.0.iter().any(|p| p.key == *key)
+    }
+    pub fn indexes_of(&self, key: &Key) -> Vec<KeyIndex> {
+        self.iter()
+            .filter(|(_, p)| p.key == *key)
+            .map(|(i, _)| i)
+            .collect()
+    }
+    pub fn label(&self, name: &str) -> Option<(KeyIndex, &Position)> {
+        self.iter().find(|(_, p)| p.label.as_deref() == Some(name))
+    }
+    pub fn find(&self, name: &str) -> Result<KeyIndex, String> {
+        if let Some((i, _)) = self.label(name) {
+            return Ok(i);
+        }
+        let key: Key = name
+            .parse()
+            .map_err(|_| format!("Unknown key or label {:?}", name))?;
+        match self.indexes_of(&key).as_slice() {
+            [i] => Ok(*i),
+            [] => Err(format!("Key {:?} not in defsrc", key)),
+            _ => Err(format!(
+                "Key {:?} is on several positions, use a :label",
+                key
+            )),
+        }
+    }
+}
+
+pub fn parse_keymap(lst: &[Expr]) -> Result<Source, String> {
+    let mut positions: Vec<Position> = Vec::with_capacity(lst.len());
+    for expr in lst {
+        let (key, label) = match expr {
+            Atom(key) => (*key, None),
+            List(items) => match items.as_slice() {
+                [Atom(key), Atom(":label"), Atom(label)] => (*key, Some(label.to_string())),
+                _ => return Err(format!("Syntax error: {}", expr)),
+            },
+        };
+        let key: Key = key.parse().map_err(|_| format!("Unknown key {}", key))?;
+        if let Some(label) = &label {
+            if label.parse::<Key>().is_ok() {
+                return Err(format!("Label {:?} is a key name", label));
+            }
+            if positions.iter().any(|p| p.label.as_ref() == Some(label)) {
+                return Err(format!("Label {:?} duplicate", label));
+            }
+        }
+        positions.push(Position { key, label });
+    }
+    KeyIndex::try_from(positions.len()).map_err(|_| "Too many keys in defsrc".to_string())?;
+    Ok(Source(positions))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Result<Source, String> {
+        parse_keymap(s_expression::from_str(s).unwrap().list().unwrap())
+    }
+
+    #[test]
+    fn labels() {
+        let source = parse("(a (spc :label lthumb) (spc :label rthumb) b)").unwrap();
+        assert_eq!(source.find("rthumb"), Ok(2.try_into().unwrap()));
+        assert_eq!(source.find("b"), Ok(3.try_into().unwrap()));
+        assert!(source.find("spc").is_err());
+        assert_eq!(source.indexes_of(&Key::Space).len(), 2);
+    }
+
+    #[test]
+    fn errors() {
+        assert!(parse("(a (b :label a))").is_err());
+        assert!(parse("(a (b :label x) (c :label x))").is_err());
+        assert!(parse("(a (b :name x))").is_err());
+        assert!(parse("(a spc spc)").is_ok());
+    }
+}
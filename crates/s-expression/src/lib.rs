@@ -1,9 +1,29 @@
 mod parse;
+mod position;
 mod tokenize;
 
 pub use parse::Expr;
+pub use position::{Position, position};
 
-pub fn from_str<'a>(input: &'a str) -> Result<parse::Expr<'a>, ()> {
-    let mut tokens = tokenize::tokenize(input);
-    return parse::parse(&mut tokens);
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error<'a> {
+    Syntax,
+    UnterminatedString(&'a str),
+}
+
+impl Error<'_> {
+    pub fn describe(&self, source: &str) -> String {
+        match self {
+            Error::Syntax => "Parse error".to_string(),
+            Error::UnterminatedString(quote) => match position(source, quote) {
+                Some(position) => format!("{}: Unterminated string", position),
+                None => "Unterminated string".to_string(),
+            },
+        }
+    }
+}
+
+pub fn from_str<'a>(input: &'a str) -> Result<parse::Expr<'a>, Error<'a>> {
+    let mut tokens = tokenize::tokenize(input).map_err(Error::UnterminatedString)?;
+    parse::parse(&mut tokens).map_err(|_| Error::Syntax)
 }
@@ -0,0 +1,47 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub line: usize,
+    pub column: usize,
+}
+
+impl std::fmt::Display for Position {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+pub fn position(source: &str, token: &str) -> Option<Position> {
+    let start = source.as_ptr() as usize;
+    let offset = (token.as_ptr() as usize).checked_sub(start)?;
+    if offset + token.len() > source.len() || !source.is_char_boundary(offset) {
+        return None;
+    }
+    let before = &source[..offset];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    Some(Position {
+        line: before.matches('\n').count() + 1,
+        column: before[line_start..].chars().count() + 1,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn atoms() {
+        let source = "(a\n  (bb c))";
+        let expr = crate::from_str(source).unwrap();
+        let list = expr.list().unwrap();
+        let inner = list[1].list().unwrap();
+        assert_eq!(
+            position(source, list[0].atom().unwrap()),
+            Some(Position { line: 1, column: 2 })
+        );
+        assert_eq!(
+            position(source, inner[1].atom().unwrap()),
+            Some(Position { line: 2, column: 7 })
+        );
+        assert_eq!(position(source, &String::from("a")), None);
+    }
+}
@@ -1,4 +1,4 @@
-pub fn tokenize(input: &str) -> Vec<&str> {
+pub fn tokenize(input: &str) -> Result<Vec<&str>, &str> {
     let mut tokens = Vec::new();
     let mut start = None;
     let mut in_comment = false;
@@ -33,6 +33,25 @@ pub fn tokenize(input: &str) -> Vec<&str> {
                 }
             }
 
+            // Строка: `"` в начале токена, за которой сразу идёт не пробел
+            '"' if start.is_none()
+                && iter
+                    .peek()
+                    .is_some_and(|&(_, n)| !n.is_whitespace() && n != ')') =>
+            {
+                let mut escaped = false;
+                let end = iter
+                    .by_ref()
+                    .find(|&(_, n)| {
+                        let end = n == '"' && !escaped;
+                        escaped = n == '\\' && !escaped;
+                        end
+                    })
+                    .ok_or(&input[i..i + 1])?
+                    .0;
+                tokens.push(&input[i..end + 1]);
+            }
+
             '(' | ')' => {
                 if let Some(s) = start {
                     tokens.push(&input[s..i]);
@@ -60,5 +79,33 @@ pub fn tokenize(input: &str) -> Vec<&str> {
         tokens.push(&input[s..]);
     }
 
-    tokens
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strings() {
+        assert_eq!(
+            tokenize(r#"(macro "hello world" spc "a \" b")"#).unwrap(),
+            vec!["(", "macro", r#""hello world""#, "spc", r#""a \" b""#, ")"]
+        );
+    }
+
+    #[test]
+    fn lone_quote() {
+        assert_eq!(
+            tokenize(r#"(" S-' ." x)"#).unwrap(),
+            vec!["(", "\"", "S-'", ".\"", "x", ")"]
+        );
+    }
+
+    #[test]
+    fn unterminated() {
+        let input = "(macro \"abc)";
+        let quote = tokenize(input).unwrap_err();
+        assert_eq!((quote, quote.as_ptr()), ("\"", input[7..].as_ptr()));
+    }
 }
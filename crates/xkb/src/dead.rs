@@ -0,0 +1,39 @@
+// Мертвые клавиши: keysym, символ, который они печатают перед пробелом,
+// и буквы, которые получаются в сочетании с ними
+
+#[rustfmt::skip]
+const DEAD: &[(&str, char, &str)] = &[
+    ("dead_grave", '`', "aàeèiìoòuùAÀEÈIÌOÒUÙ"),
+    ("dead_acute", '´', "aáeéiíoóuúyýcćnńsśzźlĺrŕAÁEÉIÍOÓUÚYÝCĆNŃSŚZŹLĹRŔ"),
+    ("dead_circumflex", '^', "aâeêiîoôuûAÂEÊIÎOÔUÛ"),
+    ("dead_tilde", '~', "aãnñoõAÃNÑOÕ"),
+    ("dead_diaeresis", '¨', "aäeëiïoöuüyÿAÄEËIÏOÖUÜYŸ"),
+    ("dead_abovering", '˚', "aåuůAÅUŮ"),
+    ("dead_cedilla", '¸', "cçsşgģkķlļnņCÇSŞGĢKĶLĻNŅ"),
+    ("dead_caron", 'ˇ', "cčdďeěnňrřsštťzžCČDĎEĚNŇRŘSŠTŤZŽ"),
+    ("dead_ogonek", '˛', "aąeęiįuųAĄEĘIĮUŲ"),
+    ("dead_doubleacute", '˝', "oőuűOŐUŰ"),
+    ("dead_breve", '˘', "aăgğuŭAĂGĞUŬ"),
+    ("dead_abovedot", '˙', "cċeėgġzżCĊEĖGĠZŻ"),
+    ("dead_macron", '¯', "aāeēiīoōuūAĀEĒIĪOŌUŪ"),
+];
+
+pub fn dead_key(name: &str) -> Option<char> {
+    DEAD.iter().find(|(n, _, _)| *n == name).map(|(_, c, _)| *c)
+}
+
+pub fn compose(dead: char, base: char) -> Option<char> {
+    compositions(dead)
+        .into_iter()
+        .find_map(|(b, result)| (b == base).then_some(result))
+}
+
+pub fn compositions(dead: char) -> Vec<(char, char)> {
+    DEAD.iter()
+        .find(|(_, c, _)| *c == dead)
+        .map(|(_, _, pairs)| {
+            let chars: Vec<char> = pairs.chars().collect();
+            chars.chunks(2).map(|pair| (pair[0], pair[1])).collect()
+        })
+        .unwrap_or_default()
+}
@@ -0,0 +1,88 @@
+// Имена keysym из X11/keysymdef.h. Для Latin-1 код keysym совпадает с кодом символа
+
+#[rustfmt::skip]
+const ASCII: [&str; 95] = [
+    "space", "exclam", "quotedbl", "numbersign", "dollar", "percent", "ampersand", "apostrophe",
+    "parenleft", "parenright", "asterisk", "plus", "comma", "minus", "period", "slash",
+    "0", "1", "2", "3", "4", "5", "6", "7", "8", "9",
+    "colon", "semicolon", "less", "equal", "greater", "question", "at",
+    "A", "B", "C", "D", "E", "F", "G", "H", "I", "J", "K", "L", "M",
+    "N", "O", "P", "Q", "R", "S", "T", "U", "V", "W", "X", "Y", "Z",
+    "bracketleft", "backslash", "bracketright", "asciicircum", "underscore", "grave",
+    "a", "b", "c", "d", "e", "f", "g", "h", "i", "j", "k", "l", "m",
+    "n", "o", "p", "q", "r", "s", "t", "u", "v", "w", "x", "y", "z",
+    "braceleft", "bar", "braceright", "asciitilde",
+];
+
+#[rustfmt::skip]
+const LATIN1: [&str; 96] = [
+    "nobreakspace", "exclamdown", "cent", "sterling", "currency", "yen", "brokenbar", "section",
+    "diaeresis", "copyright", "ordfeminine", "guillemetleft", "notsign", "hyphen", "registered", "macron",
+    "degree", "plusminus", "twosuperior", "threesuperior", "acute", "mu", "paragraph", "periodcentered",
+    "cedilla", "onesuperior", "ordmasculine", "guillemetright", "onequarter", "onehalf", "threequarters", "questiondown",
+    "Agrave", "Aacute", "Acircumflex", "Atilde", "Adiaeresis", "Aring", "AE", "Ccedilla",
+    "Egrave", "Eacute", "Ecircumflex", "Ediaeresis", "Igrave", "Iacute", "Icircumflex", "Idiaeresis",
+    "ETH", "Ntilde", "Ograve", "Oacute", "Ocircumflex", "Otilde", "Odiaeresis", "multiply",
+    "Oslash", "Ugrave", "Uacute", "Ucircumflex", "Udiaeresis", "Yacute", "THORN", "ssharp",
+    "agrave", "aacute", "acircumflex", "atilde", "adiaeresis", "aring", "ae", "ccedilla",
+    "egrave", "eacute", "ecircumflex", "ediaeresis", "igrave", "iacute", "icircumflex", "idiaeresis",
+    "eth", "ntilde", "ograve", "oacute", "ocircumflex", "otilde", "odiaeresis", "division",
+    "oslash", "ugrave", "uacute", "ucircumflex", "udiaeresis", "yacute", "thorn", "ydiaeresis",
+];
+
+#[rustfmt::skip]
+const CYRILLIC: [&str; 32] = [
+    "a", "be", "ve", "ghe", "de", "ie", "zhe", "ze", "i", "shorti", "ka", "el", "em", "en", "o", "pe",
+    "er", "es", "te", "u", "ef", "ha", "tse", "che", "sha", "shcha", "hardsign", "yeru", "softsign", "e", "yu", "ya",
+];
+
+#[rustfmt::skip]
+const OTHER: &[(&str, char)] = &[
+    ("guillemotleft", '«'), ("guillemotright", '»'), ("masculine", 'º'), ("Ooblique", 'Ø'), ("ooblique", 'ø'),
+    ("Cyrillic_io", 'ё'), ("Cyrillic_IO", 'Ё'),
+    ("Ukrainian_i", 'і'), ("Ukrainian_I", 'І'), ("Ukrainian_yi", 'ї'), ("Ukrainian_YI", 'Ї'),
+    ("Ukrainian_ie", 'є'), ("Ukrainian_IE", 'Є'),
+    ("Ukrainian_ghe_with_upturn", 'ґ'), ("Ukrainian_GHE_WITH_UPTURN", 'Ґ'),
+    ("Byelorussian_shortu", 'ў'), ("Byelorussian_SHORTU", 'Ў'),
+    ("Cyrillic_schwa", 'ә'), ("Cyrillic_SCHWA", 'Ә'), ("Cyrillic_ghe_bar", 'ғ'), ("Cyrillic_GHE_bar", 'Ғ'),
+    ("Cyrillic_ka_descender", 'қ'), ("Cyrillic_KA_descender", 'Қ'),
+    ("Cyrillic_en_descender", 'ң'), ("Cyrillic_EN_descender", 'Ң'),
+    ("Cyrillic_o_bar", 'ө'), ("Cyrillic_O_bar", 'Ө'), ("Cyrillic_u_straight", 'ү'), ("Cyrillic_U_straight", 'Ү'),
+    ("Cyrillic_u_straight_bar", 'ұ'), ("Cyrillic_U_straight_bar", 'Ұ'), ("Cyrillic_shha", 'һ'), ("Cyrillic_SHHA", 'Һ'),
+    ("numerosign", '№'), ("EuroSign", '€'), ("emdash", '—'), ("endash", '–'), ("ellipsis", '…'),
+    ("leftsinglequotemark", '‘'), ("rightsinglequotemark", '’'), ("singlelowquotemark", '‚'),
+    ("leftdoublequotemark", '“'), ("rightdoublequotemark", '”'), ("doublelowquotemark", '„'),
+    ("oe", 'œ'), ("OE", 'Œ'), ("Ydiaeresis", 'Ÿ'),
+];
+
+pub fn keysym_to_char(name: &str) -> Option<char> {
+    if let Some(i) = ASCII.iter().position(|n| *n == name) {
+        return char::from_u32(0x20 + i as u32);
+    }
+    if let Some(i) = LATIN1.iter().position(|n| *n == name) {
+        return char::from_u32(0xA0 + i as u32);
+    }
+    if let Some(rest) = name.strip_prefix("Cyrillic_") {
+        if let Some(i) = CYRILLIC.iter().position(|n| *n == rest) {
+            return char::from_u32(0x430 + i as u32);
+        }
+        if let Some(i) = CYRILLIC.iter().position(|n| n.to_uppercase() == rest) {
+            return char::from_u32(0x410 + i as u32);
+        }
+    }
+    if let Some((_, c)) = OTHER.iter().find(|(n, _)| *n == name) {
+        return Some(*c);
+    }
+    if let Some(hex) = name.strip_prefix("U") {
+        return u32::from_str_radix(hex, 16).ok().and_then(char::from_u32);
+    }
+    if let Some(hex) = name.strip_prefix("0x") {
+        let code = u32::from_str_radix(hex, 16).ok()?;
+        return match code {
+            0x0100_0000.. => char::from_u32(code - 0x0100_0000),
+            0x20..=0x7E | 0xA0..=0xFF => char::from_u32(code),
+            _ => None,
+        };
+    }
+    None
+}
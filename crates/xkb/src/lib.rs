@@ -0,0 +1,307 @@
+mod dead;
+mod keysyms;
+
+pub use dead::{compose, compositions, dead_key};
+use keys::keys::Key;
+pub use keysyms::keysym_to_char;
+
+#[derive(Debug, Default)]
+pub struct Symbols {
+    pub keys: Vec<(Key, Vec<String>)>,
+    pub skipped: Vec<String>,
+}
+
+impl Symbols {
+    fn set(&mut self, key: Key, levels: Vec<String>) {
+        match self.keys.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, current)) => *current = levels,
+            None => self.keys.push((key, levels)),
+        }
+    }
+}
+
+pub fn keycode_to_key(name: &str) -> Option<Key> {
+    Some(match name {
+        "TLDE" => Key::Grave,
+        "AE01" => Key::One,
+        "AE02" => Key::Two,
+        "AE03" => Key::Three,
+        "AE04" => Key::Four,
+        "AE05" => Key::Five,
+        "AE06" => Key::Six,
+        "AE07" => Key::Seven,
+        "AE08" => Key::Eight,
+        "AE09" => Key::Nine,
+        "AE10" => Key::Zero,
+        "AE11" => Key::Minus,
+        "AE12" => Key::Equal,
+        "AD01" => Key::Q,
+        "AD02" => Key::W,
+        "AD03" => Key::E,
+        "AD04" => Key::R,
+        "AD05" => Key::T,
+        "AD06" => Key::Y,
+        "AD07" => Key::U,
+        "AD08" => Key::I,
+        "AD09" => Key::O,
+        "AD10" => Key::P,
+        "AD11" => Key::LeftBracket,
+        "AD12" => Key::RightBracket,
+        "BKSL" => Key::Backslash,
+        "AC01" => Key::A,
+        "AC02" => Key::S,
+        "AC03" => Key::D,
+        "AC04" => Key::F,
+        "AC05" => Key::G,
+        "AC06" => Key::H,
+        "AC07" => Key::J,
+        "AC08" => Key::K,
+        "AC09" => Key::L,
+        "AC10" => Key::Semicolon,
+        "AC11" => Key::Apostrophe,
+        "AB01" => Key::Z,
+        "AB02" => Key::X,
+        "AB03" => Key::C,
+        "AB04" => Key::V,
+        "AB05" => Key::B,
+        "AB06" => Key::N,
+        "AB07" => Key::M,
+        "AB08" => Key::Comma,
+        "AB09" => Key::Dot,
+        "AB10" => Key::Slash,
+        "SPCE" => Key::Space,
+        _ => return None,
+    })
+}
+
+fn strip_comments(content: &str) -> String {
+    content
+        .lines()
+        .map(|l| l.split_once("//").map_or(l, |(before, _)| before))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn blocks(content: &str) -> Result<Vec<(&str, &str)>, String> {
+    let mut result = vec![];
+    let mut rest = content;
+    while let Some(start) = rest.find("xkb_symbols") {
+        let is_default = rest[..start].split_whitespace().any(|w| w == "default");
+        let after = &rest[start + "xkb_symbols".len()..];
+        let (name, after) = after
+            .trim_start()
+            .strip_prefix('"')
+            .and_then(|s| s.split_once('"'))
+            .ok_or("Expected variant name after xkb_symbols".to_string())?;
+        let open = after
+            .find('{')
+            .ok_or(format!("Expected {{ in {:?}", name))?;
+        let mut depth = 0;
+        let close = after[open..]
+            .char_indices()
+            .find_map(|(i, c)| {
+                match c {
+                    '{' => depth += 1,
+                    '}' => depth -= 1,
+                    _ => {}
+                };
+                (depth == 0).then_some(open + i)
+            })
+            .ok_or(format!("Unclosed variant {:?}", name))?;
+        let block = (name, &after[open + 1..close]);
+        if is_default {
+            result.insert(0, block);
+        } else {
+            result.push(block);
+        }
+        rest = &after[close + 1..];
+    }
+    Ok(result)
+}
+
+// Поля тела `key <...> { ... }`: `[..]` без имени или `name[Group1] = value`
+fn fields(body: &str) -> Vec<(Option<String>, &str)> {
+    let body = body.trim();
+    let body = body
+        .strip_prefix('{')
+        .and_then(|b| b.strip_suffix('}'))
+        .unwrap_or(body);
+    let mut result = vec![];
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut start = 0;
+    for (i, c) in body.char_indices().chain([(body.len(), ',')]) {
+        match c {
+            '"' => quoted = !quoted,
+            '[' | '(' if !quoted => depth += 1,
+            ']' | ')' if !quoted => depth -= 1,
+            ',' if !quoted && depth == 0 => {
+                let field = body[start..i].trim();
+                start = i + 1;
+                if field.starts_with('[') {
+                    result.push((None, field));
+                } else if let Some((name, value)) = field.split_once('=') {
+                    let name: String = name.split_whitespace().collect();
+                    result.push((Some(name.to_lowercase()), value.trim()));
+                }
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+fn levels(body: &str) -> Vec<String> {
+    let fields = fields(body);
+    let symbols = fields
+        .iter()
+        .find(|(name, _)| matches!(name.as_deref(), Some("symbols[group1]" | "symbols")))
+        .or_else(|| fields.iter().find(|(name, _)| name.is_none()));
+    let Some(list) = symbols.and_then(|(_, value)| value.strip_prefix('[')?.strip_suffix(']'))
+    else {
+        return vec![];
+    };
+    list.split(',').map(|s| s.trim().to_string()).collect()
+}
+
+fn apply(
+    symbols: &mut Symbols,
+    file: &str,
+    variant: Option<&str>,
+    load: &dyn Fn(&str) -> Option<String>,
+    depth: usize,
+) -> Result<(), String> {
+    if depth > 16 {
+        return Err(format!("Include depth exceeded at {}", file));
+    }
+    let Some(content) = load(file) else {
+        symbols.skipped.push(file.to_string());
+        return Ok(());
+    };
+    let content = strip_comments(&content);
+    let blocks = blocks(&content)?;
+    let (_, body) = match variant {
+        Some(v) => blocks
+            .iter()
+            .find(|(name, _)| *name == v)
+            .ok_or(format!("Variant {:?} not found in {}", v, file))?,
+        None => blocks
+            .first()
+            .ok_or(format!("No xkb_symbols in {}", file))?,
+    };
+
+    body.split(';').try_for_each(|statement| {
+        let statement = statement.trim();
+        // include не заканчивается `;`, поэтому может стоять перед следующим оператором
+        let mut statement = statement;
+        while let Some(rest) = statement.strip_prefix("include") {
+            let rest = rest.trim_start();
+            let Some((name, rest)) = rest.strip_prefix('"').and_then(|s| s.split_once('"')) else {
+                return Err(format!("Syntax error in include: {:?}", statement));
+            };
+            name.split('+')
+                .filter(|n| !n.is_empty())
+                .try_for_each(|include| {
+                    let (file, variant) = match include.split_once('(') {
+                        Some((file, variant)) => (file, Some(variant.trim_end_matches(')'))),
+                        None => (include, None),
+                    };
+                    apply(symbols, file, variant, load, depth + 1)
+                })?;
+            statement = rest.trim_start();
+        }
+        let Some(rest) = statement.strip_prefix("key") else {
+            return Ok(());
+        };
+        let rest = rest.trim_start();
+        let Some((keycode, body)) = rest.strip_prefix('<').and_then(|s| s.split_once('>')) else {
+            return Ok(());
+        };
+        if let Some(key) = keycode_to_key(keycode) {
+            let levels = levels(body);
+            if !levels.is_empty() {
+                symbols.set(key, levels);
+            }
+        }
+        Ok(())
+    })
+}
+
+pub fn parse(
+    file: &str,
+    variant: Option<&str>,
+    load: &dyn Fn(&str) -> Option<String>,
+) -> Result<Symbols, String> {
+    let mut symbols = Symbols::default();
+    if load(file).is_none() {
+        return Err(format!("Cannot read {}", file));
+    }
+    apply(&mut symbols, file, variant, load, 0)?;
+    Ok(symbols)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str) -> Option<String> {
+        std::fs::read_to_string(format!("{}/fixtures/{}", env!("CARGO_MANIFEST_DIR"), name)).ok()
+    }
+
+    fn level(symbols: &Symbols, key: Key, level: usize) -> Option<char> {
+        let (_, levels) = symbols.keys.iter().find(|(k, _)| *k == key)?;
+        keysym_to_char(levels.get(level)?)
+    }
+
+    #[test]
+    fn default_variant() {
+        let symbols = parse("ru", None, &load).unwrap();
+        assert_eq!(level(&symbols, Key::Semicolon, 0), Some('ж'));
+        assert_eq!(level(&symbols, Key::Semicolon, 1), Some('Ж'));
+        assert_eq!(level(&symbols, Key::Grave, 0), Some('ё'));
+        assert_eq!(level(&symbols, Key::Three, 1), Some('№'));
+        assert_eq!(level(&symbols, Key::Slash, 0), Some('.'));
+        assert_eq!(symbols.skipped, vec!["level3".to_string()]);
+    }
+
+    #[test]
+    fn altgr_level() {
+        let symbols = parse("ru", Some("typewriter"), &load).unwrap();
+        assert_eq!(level(&symbols, Key::Grave, 0), Some('|'));
+        assert_eq!(level(&symbols, Key::Dot, 2), Some('»'));
+        assert_eq!(level(&symbols, Key::Comma, 2), Some('«'));
+        assert_eq!(level(&symbols, Key::Q, 0), Some('й'));
+    }
+
+    #[test]
+    fn explicit_fields() {
+        let symbols = parse("fields", None, &load).unwrap();
+        assert_eq!(level(&symbols, Key::A, 2), Some('æ'));
+        assert_eq!(level(&symbols, Key::S, 1), Some('S'));
+        assert_eq!(level(&symbols, Key::S, 2), Some('ß'));
+        assert_eq!(level(&symbols, Key::D, 0), Some('d'));
+        assert_eq!(level(&symbols, Key::D, 2), None);
+        assert_eq!(level(&symbols, Key::F, 1), Some('F'));
+        assert_eq!(level(&symbols, Key::G, 0), Some('g'));
+    }
+
+    #[test]
+    fn unknown_variant() {
+        assert!(parse("ru", Some("nope"), &load).is_err());
+        assert!(parse("missing", None, &load).is_err());
+    }
+
+    #[test]
+    fn keysyms() {
+        assert_eq!(keysym_to_char("space"), Some(' '));
+        assert_eq!(keysym_to_char("asciitilde"), Some('~'));
+        assert_eq!(keysym_to_char("adiaeresis"), Some('ä'));
+        assert_eq!(keysym_to_char("Cyrillic_YA"), Some('Я'));
+        assert_eq!(keysym_to_char("U2116"), Some('№'));
+        assert_eq!(keysym_to_char("0x1000451"), Some('ё'));
+        assert_eq!(keysym_to_char("dead_acute"), None);
+        assert_eq!(dead_key("dead_acute"), Some('´'));
+        assert_eq!(compose('´', 'e'), Some('é'));
+        assert_eq!(compose('´', 'q'), None);
+    }
+}
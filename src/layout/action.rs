@@ -1,91 +1,214 @@
-use std::collections::HashMap;
-
+use super::options::split_options;
+use super::visit::ActionFold;
 use keys::keys::Key;
 use s_expression::Expr::{self, *};
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum TapHoldFlavor {
+    #[default]
+    Default,
+    HoldOnOtherKeyPress,
+    PermissiveHold,
+    ReleaseKeys(Vec<Key>),
+    ExceptKeys(Vec<Key>),
+    OppositeHand(Vec<Key>),
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TapHoldConfig {
+    pub timeout: Option<u16>,
+    pub flavor: TapHoldFlavor,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MouseDirection {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl MouseDirection {
+    pub fn key(&self) -> Key {
+        match self {
+            MouseDirection::Up => Key::MouseCursorUp,
+            MouseDirection::Down => Key::MouseCursorDown,
+            MouseDirection::Left => Key::MouseCursorLeft,
+            MouseDirection::Right => Key::MouseCursorRight,
+        }
+    }
+}
+
+impl std::str::FromStr for MouseDirection {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "up" => Self::Up,
+            "down" => Self::Down,
+            "left" => Self::Left,
+            "right" => Self::Right,
+            _ => return Err(format!("Unknown direction {:?}", s)),
+        })
+    }
+}
+
+impl std::fmt::Display for MouseDirection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            MouseDirection::Up => "up",
+            MouseDirection::Down => "down",
+            MouseDirection::Left => "left",
+            MouseDirection::Right => "right",
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VirtualKeyEvent {
+    OnPress,
+    OnRelease,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VirtualKeyAction {
+    Tap,
+    Press,
+    Release,
+    Toggle,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SwitchCondition {
+    Key(Key),
+    Layer(String),
+    History(Key, u8),
+    And(Vec<SwitchCondition>),
+    Or(Vec<SwitchCondition>),
+    Not(Box<SwitchCondition>),
+}
+
+impl SwitchCondition {
+    fn from_expr(expr: &Expr) -> Result<Self, String> {
+        let key = |k: &str| k.parse().map_err(|_| format!("Unknown key {:?}", k));
+        Ok(match expr {
+            Atom(k) => Self::Key(key(k)?),
+            List(list) => match list.as_slice() {
+                [Atom("and"), items @ ..] => Self::And(Self::list(items)?),
+                [Atom("or"), items @ ..] => Self::Or(Self::list(items)?),
+                [Atom("not"), item] => Self::Not(Box::new(Self::from_expr(item)?)),
+                [Atom("layer"), Atom(layer)] => Self::Layer(layer.to_string()),
+                [Atom("key-history"), Atom(k), Atom(n)] => match n.parse() {
+                    Ok(n @ 1..=8) => Self::History(key(k)?, n),
+                    _ => return Err(format!("Key history {:?} is not in 1..8", n)),
+                },
+                _ => return Err(format!("Unknown condition {}", expr)),
+            },
+        })
+    }
+    fn list(items: &[Expr]) -> Result<Vec<Self>, String> {
+        items.iter().map(Self::from_expr).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwitchCase {
+    pub conditions: Vec<SwitchCondition>,
+    pub action: Action,
+    pub fallthrough: bool,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Action {
     Tap(Key),
     Transparent,
     NoAction,
     Alias(String),
-    TapHold(Box<Action>, Box<Action>),
+    TapHold(Box<Action>, Box<Action>, TapHoldConfig),
     Multi(Vec<Action>),
     LayerWhileHeld(String),
     LayerSwitch(String),
+    LayerToggle(String),
+    LayerTo(String),
+    LayerOneShot(String),
+    LayerTapToggle(String),
+    LayerLock,
+    LayerOff,
     Unicode(char),
     Sequence(Vec<Action>),
-    #[allow(dead_code)]
     Hold(Key),
-    #[allow(dead_code)]
     Release(Key),
+    Delay(u16),
+    MoveMouse(MouseDirection, u16, u16),
+    MoveMouseAccel(MouseDirection, u16, u16, u16, u16),
+    MouseSpeed(u16),
+    Repeat,
+    RepeatAny,
+    AltRepeat,
+    VirtualKey(VirtualKeyEvent, VirtualKeyAction, String),
+    Switch(Vec<SwitchCase>),
+    Shifted(Box<Action>, Box<Action>),
 }
 
 impl Action {
-    pub fn resolve_aliases(&self, aliases: &HashMap<String, Action>) -> Result<Action, String> {
-        let res = match self {
-            Action::Alias(name) => aliases
-                .get(name)
-                .map(|a| a.resolve_aliases(aliases))
-                .ok_or(format!("Alias @{} not found", name))?,
-            Action::TapHold(tap, hold) => Ok(Action::TapHold(
-                Box::new(tap.resolve_aliases(aliases)?),
-                Box::new(hold.resolve_aliases(aliases)?),
-            )),
-            Action::Multi(actions) => Ok(Action::Multi(
-                actions
-                    .iter()
-                    .map(|a| a.resolve_aliases(aliases))
-                    .collect::<Result<_, _>>()?,
-            )),
-            _ => Ok(self.clone()),
-        };
-        return res;
+    pub fn overlay_layer_names<'a>(&'a self) -> Vec<&'a str> {
+        let mut names = vec![];
+        self.walk(&mut |a: &'a Action| match a {
+            Action::LayerWhileHeld(name)
+            | Action::LayerToggle(name)
+            | Action::LayerOneShot(name)
+            | Action::LayerTapToggle(name) => names.push(name.as_str()),
+            _ => {}
+        });
+        names
     }
 
-    pub fn layer_while_held_names(&self) -> Vec<&str> {
-        match self {
-            Action::LayerWhileHeld(name) => vec![name.as_str()],
-            Action::TapHold(tap, hold) => {
-                let mut v = tap.layer_while_held_names();
-                v.extend(hold.layer_while_held_names());
-                v
+    pub fn virtual_key_names<'a>(&'a self) -> Vec<&'a str> {
+        let mut names = vec![];
+        self.walk(&mut |a: &'a Action| {
+            if let Action::VirtualKey(_, _, name) = a {
+                names.push(name.as_str());
             }
-            Action::Multi(actions) | Action::Sequence(actions) => actions
-                .iter()
-                .flat_map(|a| a.layer_while_held_names())
-                .collect(),
-            _ => vec![],
-        }
+        });
+        names
     }
 
     pub fn contains_unicode(&self) -> bool {
-        match self {
-            Action::Unicode(_) => true,
-            Action::TapHold(tap, hold) => tap.contains_unicode() || hold.contains_unicode(),
-            Action::Multi(actions) | Action::Sequence(actions) => {
-                actions.iter().any(|a| a.contains_unicode())
-            }
-            _ => false,
-        }
+        let mut found = false;
+        self.walk(&mut |a: &Action| found |= matches!(a, Action::Unicode(_)));
+        found
     }
 
-    pub fn map_layer_while_held(&mut self, f: &impl Fn(&str) -> Option<String>) {
-        match self {
-            Action::LayerWhileHeld(name) => {
-                if let Some(new) = f(name) {
-                    *name = new;
-                }
-            }
-            Action::TapHold(tap, hold) => {
-                tap.map_layer_while_held(f);
-                hold.map_layer_while_held(f);
-            }
-            Action::Multi(actions) | Action::Sequence(actions) => {
-                actions.iter_mut().for_each(|a| a.map_layer_while_held(f));
+    pub fn map_layer_names(&mut self, f: &impl Fn(&str) -> Option<String>) {
+        let action = std::mem::replace(self, Action::NoAction);
+        let Ok(action) = action.fold(&mut RenameLayers(f));
+        *self = action;
+    }
+
+    fn macro_item(expr: &Expr) -> Result<Vec<Action>, String> {
+        Ok(match expr {
+            Atom(s) if s.len() > 1 && s.starts_with('"') && s.ends_with('"') => {
+                text_to_actions(&s[1..s.len() - 1])?
             }
-            _ => {}
-        }
+            List(list) => match list.as_slice() {
+                [Atom("press"), Atom(key)] => {
+                    vec![Action::Hold(
+                        key.parse().map_err(|_| format!("Unknown key {:?}", key))?,
+                    )]
+                }
+                [Atom("release"), Atom(key)] => {
+                    vec![Action::Release(
+                        key.parse().map_err(|_| format!("Unknown key {:?}", key))?,
+                    )]
+                }
+                [Atom("delay"), Atom(ms)] => {
+                    vec![Action::Delay(
+                        ms.parse().map_err(|_| format!("Invalid delay {:?}", ms))?,
+                    )]
+                }
+                _ => vec![Self::from_expr(expr)?],
+            },
+            _ => vec![Self::from_expr(expr)?],
+        })
     }
 
     pub fn from_expr(expr: &Expr) -> Result<Action, String> {
@@ -122,6 +245,8 @@ impl Action {
                         "_" => Action::Transparent,
                         "lb" => Self::Unicode('('),
                         "rb" => Self::Unicode(')'),
+                        "rpt" => Action::Repeat,
+                        "arpt" => Action::AltRepeat,
                         k => Action::Tap(k.parse().map_err(|_| format!("Unknown key {:?}", k))?),
                     }
                 }
@@ -132,12 +257,50 @@ impl Action {
                 };
                 match *name {
                     "tap-hold" => {
+                        let (params, mut options) = split_options(params)?;
                         let [tap, hold] = params else {
                             return Err(format!("Syntax error"));
                         };
                         let tap = Self::from_expr(tap)?;
                         let hold = Self::from_expr(hold)?;
-                        Action::TapHold(Box::new(tap), Box::new(hold))
+                        let timeout = options.take_parsed("timeout")?;
+                        let keys = options
+                            .take_list("keys")?
+                            .map(|keys| {
+                                keys.iter()
+                                    .map(|k| k.parse().map_err(|_| format!("Unknown key {:?}", k)))
+                                    .collect::<Result<Vec<Key>, _>>()
+                            })
+                            .transpose()?;
+                        let flavor = match (options.take_atom("flavor")?, keys) {
+                            (None, None) => TapHoldFlavor::Default,
+                            (Some("hold-on-other-key-press" | "press"), None) => {
+                                TapHoldFlavor::HoldOnOtherKeyPress
+                            }
+                            (Some("permissive-hold" | "permissive"), None) => {
+                                TapHoldFlavor::PermissiveHold
+                            }
+                            (Some("opposite-hand"), None) => TapHoldFlavor::OppositeHand(vec![]),
+                            (Some("release-keys") | None, Some(keys)) => {
+                                TapHoldFlavor::ReleaseKeys(keys)
+                            }
+                            (Some("except-keys"), Some(keys)) => TapHoldFlavor::ExceptKeys(keys),
+                            (Some(f @ ("release-keys" | "except-keys")), None) => {
+                                return Err(format!("Flavor {} requires :keys", f));
+                            }
+                            (Some(_), Some(_)) => {
+                                return Err(
+                                    ":keys requires flavor release-keys or except-keys".to_string()
+                                );
+                            }
+                            (Some(f), None) => return Err(format!("Unknown flavor {:?}", f)),
+                        };
+                        options.finish()?;
+                        Action::TapHold(
+                            Box::new(tap),
+                            Box::new(hold),
+                            TapHoldConfig { timeout, flavor },
+                        )
                     }
                     "multi" => {
                         let actions: Vec<Action> = params
@@ -146,17 +309,133 @@ impl Action {
                             .collect::<Result<_, _>>()?;
                         Action::Multi(actions)
                     }
-                    "layer-while-held" => {
-                        let [Atom(name)] = params else {
+                    "layer-while-held" | "layer-switch" | "layer-toggle" | "layer-to"
+                    | "layer-one-shot" | "layer-tap-toggle" => {
+                        let [Atom(layer)] = params else {
                             return Err(format!("Syntax error"));
                         };
-                        Action::LayerWhileHeld(name.to_string())
+                        let layer = layer.to_string();
+                        match *name {
+                            "layer-while-held" => Action::LayerWhileHeld(layer),
+                            "layer-switch" => Action::LayerSwitch(layer),
+                            "layer-toggle" => Action::LayerToggle(layer),
+                            "layer-to" => Action::LayerTo(layer),
+                            "layer-one-shot" => Action::LayerOneShot(layer),
+                            _ => Action::LayerTapToggle(layer),
+                        }
                     }
-                    "layer-switch" => {
-                        let [Atom(name)] = params else {
-                            return Err(format!("Syntax error"));
+                    "macro" => {
+                        let actions = params
+                            .iter()
+                            .map(Self::macro_item)
+                            .collect::<Result<Vec<_>, _>>()?
+                            .concat();
+                        if actions.is_empty() {
+                            return Err("Empty macro".to_string());
+                        }
+                        Action::Sequence(actions)
+                    }
+                    "movemouse" | "movemouse-accel" => {
+                        let [Atom(direction), numbers @ ..] = params else {
+                            return Err("Syntax error".to_string());
                         };
-                        Action::LayerSwitch(name.to_string())
+                        let direction = direction.parse()?;
+                        let numbers = numbers
+                            .iter()
+                            .map(|n| number(n.atom()?))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        match (*name, numbers.as_slice()) {
+                            ("movemouse", [interval, distance]) => {
+                                Action::MoveMouse(direction, *interval, *distance)
+                            }
+                            ("movemouse-accel", [interval, accel, min, max]) => {
+                                if min > max {
+                                    return Err(format!("Min distance {} > max {}", min, max));
+                                }
+                                Action::MoveMouseAccel(direction, *interval, *accel, *min, *max)
+                            }
+                            _ => return Err(format!("Syntax error: {}", expr)),
+                        }
+                    }
+                    "movemouse-speed" => {
+                        let [Atom(speed)] = params else {
+                            return Err("Syntax error".to_string());
+                        };
+                        Action::MouseSpeed(number(speed)?)
+                    }
+                    "on-press" | "on-release" => {
+                        let [Atom(action), Atom(vkey)] = params else {
+                            return Err(format!("Syntax error: {}", expr));
+                        };
+                        let event = match *name {
+                            "on-press" => VirtualKeyEvent::OnPress,
+                            _ => VirtualKeyEvent::OnRelease,
+                        };
+                        let action = match *action {
+                            "tap" => VirtualKeyAction::Tap,
+                            "press" => VirtualKeyAction::Press,
+                            "release" => VirtualKeyAction::Release,
+                            "toggle" => VirtualKeyAction::Toggle,
+                            _ => return Err(format!("Unknown virtual key action {:?}", action)),
+                        };
+                        Action::VirtualKey(event, action, vkey.to_string())
+                    }
+                    "shifted" => {
+                        let [base, shifted] = params else {
+                            return Err(format!("Syntax error: {}", expr));
+                        };
+                        Action::Shifted(
+                            Box::new(Self::from_expr(base)?),
+                            Box::new(Self::from_expr(shifted)?),
+                        )
+                    }
+                    "switch" => {
+                        if params.is_empty() || params.len() % 3 != 0 {
+                            return Err(format!("Syntax error: {}", expr));
+                        }
+                        let cases = params
+                            .chunks(3)
+                            .map(|x| {
+                                let [List(conditions), action, Atom(end)] = x else {
+                                    return Err(format!("Syntax error: {:?}", x));
+                                };
+                                Ok(SwitchCase {
+                                    conditions: SwitchCondition::list(conditions)?,
+                                    action: Self::from_expr(action)?,
+                                    fallthrough: match *end {
+                                        "break" => false,
+                                        "fallthrough" => true,
+                                        _ => {
+                                            return Err(format!(
+                                                "Expected break or fallthrough, found {:?}",
+                                                end
+                                            ));
+                                        }
+                                    },
+                                })
+                            })
+                            .collect::<Result<_, String>>()?;
+                        Action::Switch(cases)
+                    }
+                    "repeat" => match params {
+                        [] => Action::Repeat,
+                        [Atom("any")] => Action::RepeatAny,
+                        _ => return Err(format!("Syntax error: {}", expr)),
+                    },
+                    "alt-repeat" => {
+                        if !params.is_empty() {
+                            return Err(format!("Syntax error: {}", expr));
+                        }
+                        Action::AltRepeat
+                    }
+                    "layer-lock" | "layer-off" => {
+                        if !params.is_empty() {
+                            return Err(format!("Syntax error"));
+                        }
+                        match *name {
+                            "layer-lock" => Action::LayerLock,
+                            _ => Action::LayerOff,
+                        }
                     }
                     _ => return Err(format!("Unknown action {}", name)),
                 }
@@ -164,3 +443,75 @@ impl Action {
         })
     }
 }
+
+struct RenameLayers<'a, F>(&'a F);
+
+impl<F: Fn(&str) -> Option<String>> ActionFold for RenameLayers<'_, F> {
+    type Error = std::convert::Infallible;
+    fn fold(&mut self, mut action: Action) -> Result<Action, Self::Error> {
+        if let Action::LayerWhileHeld(name)
+        | Action::LayerSwitch(name)
+        | Action::LayerToggle(name)
+        | Action::LayerTo(name)
+        | Action::LayerOneShot(name)
+        | Action::LayerTapToggle(name) = &mut action
+            && let Some(new) = (self.0)(name)
+        {
+            *name = new;
+        }
+        Ok(action)
+    }
+    fn fold_condition(
+        &mut self,
+        mut condition: SwitchCondition,
+    ) -> Result<SwitchCondition, Self::Error> {
+        if let SwitchCondition::Layer(name) = &mut condition
+            && let Some(new) = (self.0)(name)
+        {
+            *name = new;
+        }
+        Ok(condition)
+    }
+}
+
+fn number(s: &str) -> Result<u16, String> {
+    s.parse()
+        .map_err(|_| format!("Expected number, found {:?}", s))
+}
+
+fn text_to_actions(text: &str) -> Result<Vec<Action>, String> {
+    let mut chars = text.chars();
+    let mut actions = Vec::with_capacity(text.len());
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some('t') => '\t',
+                Some(c) => c,
+                None => return Err(format!("Unfinished escape in {:?}", text)),
+            },
+            c => c,
+        };
+        actions.push(match c {
+            ' ' => Action::Tap(Key::Space),
+            '\n' => Action::Tap(Key::Enter),
+            '\t' => Action::Tap(Key::Tab),
+            c if c.is_ascii_lowercase() || c.is_ascii_digit() => Action::Tap(
+                c.to_string()
+                    .parse()
+                    .map_err(|_| format!("Unknown key {:?}", c))?,
+            ),
+            c if c.is_ascii_uppercase() => Action::Multi(vec![
+                Action::Tap(Key::LeftShift),
+                Action::Tap(
+                    c.to_ascii_lowercase()
+                        .to_string()
+                        .parse()
+                        .map_err(|_| format!("Unknown key {:?}", c))?,
+                ),
+            ]),
+            c => Action::Unicode(c),
+        });
+    }
+    Ok(actions)
+}
@@ -0,0 +1,157 @@
+use super::action::Action;
+use super::visit::ActionFold;
+use s_expression::Expr::{self, *};
+use std::collections::HashMap;
+
+#[derive(Debug, Default)]
+pub struct Aliases {
+    global: HashMap<String, Action>,
+    layers: HashMap<String, HashMap<String, Action>>,
+}
+
+impl Aliases {
+    pub fn parse(&mut self, params: &[Expr]) -> Result<(), String> {
+        let (target, prefix, params) = match params {
+            [List(scope), rest @ ..] => match scope.as_slice() {
+                [Atom("namespace"), Atom(ns)] => {
+                    check_name(ns)?;
+                    (&mut self.global, format!("{}/", ns), rest)
+                }
+                [Atom("layer"), Atom(layer)] => (
+                    self.layers.entry(layer.to_string()).or_default(),
+                    String::new(),
+                    rest,
+                ),
+                _ => return Err(format!("Unknown alias scope {:?}", scope)),
+            },
+            _ => (&mut self.global, String::new(), params),
+        };
+        if !params.len().is_multiple_of(2) {
+            return Err(format!("Syntax error: {:?}", params));
+        }
+        params.chunks(2).try_for_each(|x| {
+            let [Atom(name), expr] = x else {
+                return Err(format!("Syntax error: {:?}", x));
+            };
+            check_name(name)?;
+            target.insert(format!("{}{}", prefix, name), Action::from_expr(expr)?);
+            Ok(())
+        })
+    }
+
+    pub fn layers(&self) -> impl Iterator<Item = &str> {
+        self.layers.keys().map(|l| l.as_str())
+    }
+}
+
+fn check_name(name: &str) -> Result<(), String> {
+    if name.contains('/') {
+        return Err(format!("Alias name {:?} can't contain /", name));
+    }
+    Ok(())
+}
+
+struct ResolveAliases<'a> {
+    aliases: &'a Aliases,
+    layer: Option<&'a str>,
+    stack: Vec<String>,
+}
+
+impl ResolveAliases<'_> {
+    fn find(&self, name: &str) -> Option<(String, &Action)> {
+        let namespace = self
+            .stack
+            .last()
+            .and_then(|outer| outer.split_once('/'))
+            .map(|(ns, _)| format!("{}/{}", ns, name));
+        let scoped = self
+            .layer
+            .and_then(|l| self.aliases.layers.get(l))
+            .and_then(|l| l.get(name));
+        namespace
+            .and_then(|full| Some((full.clone(), self.aliases.global.get(&full)?)))
+            .or(scoped.map(|a| (format!("{}:{}", self.layer.unwrap_or_default(), name), a)))
+            .or(self.aliases.global.get(name).map(|a| (name.to_string(), a)))
+    }
+}
+
+impl ActionFold for ResolveAliases<'_> {
+    type Error = String;
+    fn fold(&mut self, action: Action) -> Result<Action, String> {
+        let Action::Alias(name) = action else {
+            return Ok(action);
+        };
+        let (full, alias) = self
+            .find(&name)
+            .ok_or(format!("Alias @{} not found", name))?;
+        if let Some(start) = self.stack.iter().position(|x| *x == full) {
+            let cycle: Vec<_> = self.stack[start..]
+                .iter()
+                .chain([&full])
+                .map(|x| format!("@{}", x))
+                .collect();
+            return Err(format!("Alias cycle: {}", cycle.join(" -> ")));
+        }
+        let alias = alias.clone();
+        self.stack.push(full);
+        let result = alias.fold(self);
+        self.stack.pop();
+        result
+    }
+}
+
+impl Action {
+    pub fn resolve_aliases(
+        &self,
+        aliases: &Aliases,
+        layer: Option<&str>,
+    ) -> Result<Action, String> {
+        self.clone().fold(&mut ResolveAliases {
+            aliases,
+            layer,
+            stack: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::keys::Key;
+
+    fn aliases(defs: &[&str]) -> Aliases {
+        let mut aliases = Aliases::default();
+        for def in defs {
+            let expr = s_expression::from_str(def).unwrap();
+            aliases.parse(expr.list().unwrap()).unwrap();
+        }
+        aliases
+    }
+
+    fn resolve(aliases: &Aliases, name: &str, layer: Option<&str>) -> Result<Action, String> {
+        Action::Alias(name.to_string()).resolve_aliases(aliases, layer)
+    }
+
+    #[test]
+    fn cycle() {
+        let a = aliases(&["(a @b b (macro x @c) c @a)"]);
+        assert_eq!(
+            resolve(&a, "a", None),
+            Err("Alias cycle: @a -> @b -> @c -> @a".to_string())
+        );
+    }
+
+    #[test]
+    fn scopes() {
+        let a = aliases(&[
+            "(x a y @x)",
+            "((namespace kb) x b y @x z @y)",
+            "((layer nav) x c)",
+        ]);
+        assert_eq!(resolve(&a, "y", None), Ok(Action::Tap(Key::A)));
+        assert_eq!(resolve(&a, "kb/y", None), Ok(Action::Tap(Key::B)));
+        assert_eq!(resolve(&a, "kb/z", None), Ok(Action::Tap(Key::B)));
+        assert_eq!(resolve(&a, "y", Some("nav")), Ok(Action::Tap(Key::C)));
+        assert!(resolve(&a, "kb/q", None).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+use s_expression::Expr::{self, *};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Bool,
+    Number,
+    Text,
+}
+
+#[rustfmt::skip]
+const KANATA_OPTIONS: &[(&str, Kind)] = &[
+    ("process-unmapped-keys", Kind::Bool),
+    ("danger-enable-cmd", Kind::Bool),
+    ("sequence-timeout", Kind::Number),
+    ("sequence-input-mode", Kind::Text),
+    ("sequence-backtrack-modcancel", Kind::Bool),
+    ("log-layer-changes", Kind::Bool),
+    ("delegate-to-first-layer", Kind::Bool),
+    ("movemouse-inherit-accel-state", Kind::Bool),
+    ("movemouse-smooth-diagonals", Kind::Bool),
+    ("concurrent-tap-hold", Kind::Bool),
+    ("rapid-event-delay", Kind::Number),
+    ("chords-v2-min-idle", Kind::Number),
+    ("tap-hold-require-prior-idle", Kind::Number),
+    ("override-release-on-activation", Kind::Bool),
+    ("allow-hardware-repeat", Kind::Bool),
+    ("alias-to-trigger-on-load", Kind::Text),
+    ("linux-dev", Kind::Text),
+    ("linux-dev-names-include", Kind::Text),
+    ("linux-dev-names-exclude", Kind::Text),
+    ("linux-continue-if-no-devs-found", Kind::Bool),
+    ("linux-unicode-u-code", Kind::Text),
+    ("linux-unicode-termination", Kind::Text),
+    ("linux-x11-repeat-delay-rate", Kind::Text),
+    ("windows-altgr", Kind::Text),
+    ("macos-dev-names-include", Kind::Text),
+    ("macos-dev-names-exclude", Kind::Text),
+];
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnicodeInput {
+    #[default]
+    Kanata,
+    Linux,
+    Windows,
+    Error,
+}
+
+impl std::str::FromStr for UnicodeInput {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "kanata" => Self::Kanata,
+            "linux" => Self::Linux,
+            "windows" => Self::Windows,
+            "error" => Self::Error,
+            _ => return Err(()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Transparency {
+    #[default]
+    Flatten,
+    Runtime,
+}
+
+impl std::str::FromStr for Transparency {
+    type Err = ();
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "flatten" => Self::Flatten,
+            "runtime" => Self::Runtime,
+            _ => return Err(()),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct VialConfig {
+    pub version: u32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct KanataConfig {
+    pub options: Vec<(String, String)>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub tapping_term: u16,
+    pub one_shot_timeout: u16,
+    pub unicode_input: UnicodeInput,
+    pub transparency: Transparency,
+    pub check_rows: bool,
+    pub vial: VialConfig,
+    pub kanata: KanataConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tapping_term: 200,
+            one_shot_timeout: 2000,
+            unicode_input: Default::default(),
+            transparency: Default::default(),
+            check_rows: true,
+            vial: VialConfig { version: 6 },
+            kanata: Default::default(),
+        }
+    }
+}
+
+fn parse_value<T: std::str::FromStr>(name: &str, value: &Expr) -> Result<T, String> {
+    let value = value.atom()?;
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value {:?} for {}", value, name))
+}
+
+fn parse_bool(name: &str, value: &Expr) -> Result<bool, String> {
+    match value.atom()? {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        value => Err(format!(
+            "Invalid value {:?} for {}, expected yes or no",
+            value, name
+        )),
+    }
+}
+
+fn check_kind(name: &str, value: &str, kind: Kind) -> Result<(), String> {
+    let valid = match kind {
+        Kind::Bool => matches!(value, "yes" | "no"),
+        Kind::Number => value.parse::<u32>().is_ok(),
+        Kind::Text => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(format!(
+            "Invalid value {:?} for {}, expected {:?}",
+            value, name, kind
+        ))
+    }
+}
+
+fn pairs<'a, 'b>(params: &'b [Expr<'a>]) -> Result<Vec<(&'a str, &'b Expr<'a>)>, String> {
+    if !params.len().is_multiple_of(2) {
+        return Err(format!("Syntax error: {:?}", params));
+    }
+    params
+        .chunks(2)
+        .map(|x| match x {
+            [Atom(name), value] => Ok((*name, value)),
+            _ => Err(format!("Syntax error: {:?}", x)),
+        })
+        .collect()
+}
+
+impl Config {
+    pub fn parse(&mut self, params: &[Expr]) -> Result<(), String> {
+        let mut rest = params;
+        while let [first, tail @ ..] = rest {
+            rest = match first {
+                List(section) => {
+                    let [Atom(name), params @ ..] = section.as_slice() else {
+                        return Err(format!("Expected section name, found {}", first));
+                    };
+                    match *name {
+                        "vial" => self.vial.parse(params)?,
+                        "kanata" => self.kanata.parse(params)?,
+                        _ => return Err(format!("Unknown section {}", name)),
+                    };
+                    tail
+                }
+                Atom(name) => {
+                    let [value, tail @ ..] = tail else {
+                        return Err(format!("Option {} requires a value", name));
+                    };
+                    match *name {
+                        "tapping-term" => self.tapping_term = parse_value(name, value)?,
+                        "one-shot-timeout" => {
+                            self.one_shot_timeout = parse_value(name, value)?
+                        }
+                        "unicode-input" => self.unicode_input = parse_value(name, value)?,
+                        "transparency" => self.transparency = parse_value(name, value)?,
+                        "check-rows" => self.check_rows = parse_bool(name, value)?,
+                        _ => return Err(format!("Unknown option {}", name)),
+                    };
+                    tail
+                }
+            };
+        }
+        Ok(())
+    }
+}
+
+impl VialConfig {
+    fn parse(&mut self, params: &[Expr]) -> Result<(), String> {
+        pairs(params)?.into_iter().try_for_each(|(name, value)| {
+            match name {
+                "version" => self.version = parse_value(name, value)?,
+                _ => return Err(format!("Unknown vial option {}", name)),
+            };
+            Ok(())
+        })
+    }
+}
+
+impl KanataConfig {
+    fn parse(&mut self, params: &[Expr]) -> Result<(), String> {
+        pairs(params)?.into_iter().try_for_each(|(name, value)| {
+            let (_, kind) = KANATA_OPTIONS
+                .iter()
+                .find(|(n, _)| *n == name)
+                .ok_or(format!("Unknown kanata option {}", name))?;
+            let value = value.atom()?;
+            check_kind(name, value, *kind)?;
+            match self.options.iter_mut().find(|(n, _)| n == name) {
+                Some(option) => option.1 = value.to_string(),
+                None => self.options.push((name.to_string(), value.to_string())),
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(input: &str) -> Result<Config, String> {
+        let expr = s_expression::from_str(input).unwrap();
+        let mut config = Config::default();
+        config.parse(expr.list()?)?;
+        Ok(config)
+    }
+
+    #[test]
+    fn sections() {
+        let config = parse(
+            "(tapping-term 180 (vial version 5) (kanata process-unmapped-keys yes))",
+        )
+        .unwrap();
+        assert_eq!(config.tapping_term, 180);
+        assert_eq!(config.vial.version, 5);
+        assert_eq!(
+            config.kanata.options,
+            vec![("process-unmapped-keys".to_string(), "yes".to_string())]
+        );
+    }
+
+    #[test]
+    fn validation() {
+        assert!(parse("(tapping-term fast)").is_err());
+        assert!(parse("(unknown 1)").is_err());
+        assert!(parse("((kanata process-unmapped-keys maybe))").is_err());
+        assert!(parse("((kanata not-an-option yes))").is_err());
+        assert!(parse("((zmk a b))").is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use keys::keys::Key;
+use parser::{Matrix, Source};
+use s_expression::Expr::{self, *};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hand {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Hands(HashMap<Key, Hand>);
+
+impl Hands {
+    pub fn parse(&mut self, params: &[Expr]) -> Result<(), String> {
+        if !params.len().is_multiple_of(2) {
+            return Err(format!("Syntax error: {:?}", params));
+        }
+        params.chunks(2).try_for_each(|x| {
+            let [Atom(hand), List(keys)] = x else {
+                return Err(format!("Syntax error: {:?}", x));
+            };
+            let hand = match *hand {
+                "left" => Hand::Left,
+                "right" => Hand::Right,
+                _ => return Err(format!("Unknown hand {:?}", hand)),
+            };
+            keys.iter().try_for_each(|k| {
+                let k = k.atom()?;
+                let key: Key = k.parse().map_err(|_| format!("Unknown key {:?}", k))?;
+                match self.0.insert(key, hand) {
+                    Some(h) if h != hand => Err(format!("Key {:?} is in both hands", key)),
+                    _ => Ok(()),
+                }
+            })
+        })
+    }
+
+    pub fn from_matrix(matrix: &Matrix) -> Self {
+        let items = matrix.items();
+        let (min, max) = items.iter().fold((f32::MAX, f32::MIN), |(min, max), i| {
+            (min.min(i.center_x()), max.max(i.center_x()))
+        });
+        let middle = (min + max) / 2.0;
+        Self(
+            items
+                .iter()
+                .map(|i| {
+                    let hand = if i.center_x() < middle {
+                        Hand::Left
+                    } else {
+                        Hand::Right
+                    };
+                    (*i.key(), hand)
+                })
+                .collect(),
+        )
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn same_hand(&self, key: &Key, source: &Source) -> Result<Vec<Key>, String> {
+        let hand = self.0.get(key).ok_or(format!(
+            "Hand of key {:?} is unknown, add it to defhands",
+            key
+        ))?;
+        let mut keys: Vec<Key> = vec![];
+        source
+            .iter()
+            .map(|(_, p)| p.key)
+            .filter(|k| k != key && self.0.get(k) == Some(hand))
+            .for_each(|k| {
+                if !keys.contains(&k) {
+                    keys.push(k);
+                }
+            });
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defhands() {
+        let expr = s_expression::from_str("(left (a s d) right (j k l))").unwrap();
+        let mut hands = Hands::default();
+        hands.parse(expr.list().unwrap()).unwrap();
+        let source = parser::parse_keymap(
+            s_expression::from_str("(a s d j k l)")
+                .unwrap()
+                .list()
+                .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(hands.same_hand(&Key::S, &source), Ok(vec![Key::A, Key::D]));
+        assert!(hands.same_hand(&Key::Q, &source).is_err());
+
+        let expr = s_expression::from_str("(left (a) right (a))").unwrap();
+        assert!(Hands::default().parse(expr.list().unwrap()).is_err());
+    }
+
+    #[test]
+    fn matrix() {
+        let keyboard = parser::parse("imperial44").unwrap();
+        let hands = Hands::from_matrix(&keyboard.matrix);
+        let same = hands.same_hand(&Key::F, &keyboard.source).unwrap();
+        assert!(same.contains(&Key::A));
+        assert!(!same.contains(&Key::J));
+    }
+}
@@ -1,75 +1,216 @@
+use super::options::split_options;
 use crate::layout::action::Action;
 use keys::keys::{Key, KeyIndex};
+use parser::Source;
 use s_expression::Expr::{self, *};
-use std::{collections::HashMap, str::FromStr};
+use std::{
+    collections::{HashMap, HashSet},
+    str::FromStr,
+};
 
 #[derive(Debug, Clone)]
 pub struct Override {
     pub key: Key,
     pub mods: Vec<Key>,
     pub action: Action,
+    pub options: OverrideOptions,
 }
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
-pub enum Keymap {
-    En,
-    Ru,
+pub struct OverrideOptions {
+    pub negative_mods: Vec<Key>,
+    pub suppressed_mods: Option<Vec<Key>>,
+    pub one_mod: bool,
+    pub activation_trigger_down: bool,
+    pub activation_required_mod_down: bool,
+    pub activation_negative_mod_up: bool,
+    pub no_reregister_trigger: bool,
+    pub no_unregister_on_other_key_down: bool,
+}
+
+impl Default for OverrideOptions {
+    fn default() -> Self {
+        Self {
+            negative_mods: vec![],
+            suppressed_mods: None,
+            one_mod: false,
+            activation_trigger_down: true,
+            activation_required_mod_down: true,
+            activation_negative_mod_up: true,
+            no_reregister_trigger: false,
+            no_unregister_on_other_key_down: false,
+        }
+    }
+}
+
+impl OverrideOptions {
+    pub fn parse(params: &[Expr]) -> Result<Self, String> {
+        let (positional, mut options) = split_options(params)?;
+        if !positional.is_empty() {
+            return Err(format!("Syntax error: {:?}", positional));
+        }
+        let mut mods = |name: &str| {
+            options
+                .take_list(name)?
+                .map(|keys| {
+                    keys.iter()
+                        .map(|k| match k.parse::<Key>() {
+                            Ok(k) if k.is_modifier() => Ok(k),
+                            _ => Err(format!("Expected modifier, found {:?}", k)),
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                })
+                .transpose()
+        };
+        let mut result = Self {
+            negative_mods: mods("negative-mods")?.unwrap_or_default(),
+            suppressed_mods: mods("suppressed-mods")?,
+            ..Default::default()
+        };
+        if let Some(activation) = options.take_list("activation")? {
+            result.activation_trigger_down = false;
+            result.activation_required_mod_down = false;
+            result.activation_negative_mod_up = false;
+            for a in activation {
+                match a {
+                    "trigger-down" => result.activation_trigger_down = true,
+                    "mod-down" => result.activation_required_mod_down = true,
+                    "negative-mod-up" => result.activation_negative_mod_up = true,
+                    _ => return Err(format!("Unknown activation {:?}", a)),
+                }
+            }
+        }
+        if let Some(x) = options.take_bool("one-mod")? {
+            result.one_mod = x;
+        }
+        if let Some(x) = options.take_bool("no-reregister-trigger")? {
+            result.no_reregister_trigger = x;
+        }
+        if let Some(x) = options.take_bool("no-unregister-on-other-key-down")? {
+            result.no_unregister_on_other_key_down = x;
+        }
+        options.finish()?;
+        Ok(result)
+    }
+}
+
+pub fn override_entries<'a, 'b>(params: &'b [Expr<'a>]) -> Result<Vec<&'b [Expr<'a>]>, String> {
+    let is_option = |e: &Expr| matches!(e, Atom(x) if x.starts_with(":") && x.len() > 1);
+    let mut entries = vec![];
+    let mut rest = params;
+    while !rest.is_empty() {
+        let mut end = 2;
+        while rest.get(end).is_some_and(is_option) {
+            end += 2;
+        }
+        if end > rest.len() {
+            return Err(format!("Syntax error: {:?}", rest));
+        }
+        let (entry, tail) = rest.split_at(end);
+        entries.push(entry);
+        rest = tail;
+    }
+    Ok(entries)
 }
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Keymap(String);
 
 impl Default for Keymap {
     fn default() -> Self {
-        Keymap::En
+        Keymap("en".to_string())
     }
 }
 impl FromStr for Keymap {
     type Err = ();
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(match s.to_lowercase().as_str() {
-            "ru" => Self::Ru,
-            "en" => Self::En,
-            _ => return Err(()),
-        })
+        if s.is_empty() || !s.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_') {
+            return Err(());
+        }
+        Ok(Self(s.to_lowercase()))
+    }
+}
+impl std::fmt::Display for Keymap {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub type Groups = HashMap<String, Vec<Key>>;
+
+pub fn parse_groups(params: &[Expr], groups: &mut Groups) -> Result<(), String> {
+    if !params.len().is_multiple_of(2) {
+        return Err(format!("Syntax error: {:?}", params));
     }
+    params.chunks(2).try_for_each(|x| {
+        let [Atom(name), List(keys)] = x else {
+            return Err(format!("Syntax error: {:?}", x));
+        };
+        let keys = keys
+            .iter()
+            .map(|k| {
+                let k = k.atom()?;
+                k.parse().map_err(|_| format!("Unknown key {:?}", k))
+            })
+            .collect::<Result<Vec<Key>, _>>()?;
+        if groups.insert(name.to_string(), keys).is_some() {
+            return Err(format!("Group {} already defined", name));
+        }
+        Ok(())
+    })
 }
 
 #[derive(Debug, Default, Clone)]
 pub struct Layer {
     pub name: String,
-    pub parent: String,
+    pub parents: Vec<String>,
     pub keys: HashMap<KeyIndex, Action>,
     pub overrides: Vec<Override>,
     pub index: usize,
     pub keymap: Keymap,
+    pub transparent: HashSet<KeyIndex>,
 }
 
 impl Layer {
-    pub fn child(&self, name: String, index: usize) -> Self {
+    pub fn child(parents: &[&Layer], name: String, index: usize) -> Self {
         Self {
             name: name,
-            parent: self.name.clone(),
-            keys: self.keys.clone(),
-            overrides: self.overrides.clone(),
+            parents: parents.iter().map(|p| p.name.clone()).collect(),
+            keys: parents
+                .iter()
+                .flat_map(|p| p.keys.keys())
+                .map(|k| (*k, Action::Transparent))
+                .collect(),
+            overrides: parents
+                .iter()
+                .flat_map(|p| p.overrides.iter().cloned())
+                .collect(),
             index: index,
-            keymap: self.keymap.clone(),
+            keymap: parents
+                .first()
+                .map(|p| p.keymap.clone())
+                .unwrap_or_default(),
+            transparent: Default::default(),
         }
     }
-    pub fn from_keyboard(source: &HashMap<Key, KeyIndex>) -> Self {
+    pub fn from_keyboard(source: &Source) -> Self {
         Self {
             name: "src".to_string(),
-            parent: String::new(),
+            parents: vec![],
             keys: source
                 .iter()
-                .map(|(k, v)| (*v, Action::Tap(k.clone())))
+                .map(|(i, p)| (i, Action::Tap(p.key)))
                 .collect(),
             overrides: Default::default(),
             index: 0,
             keymap: Default::default(),
+            transparent: Default::default(),
         }
     }
     pub fn from_def(params: &[Expr<'_>], index: usize) -> Result<Self, String> {
-        let (name, parent, actions) = Self::get_name(params)?;
+        let (name, parents, actions) = Self::get_name(params)?;
         Ok(Self {
             name: name.to_string(),
-            parent: parent.to_string(),
+            parents: parents.iter().map(|p| p.to_string()).collect(),
             keys: actions.iter().enumerate().try_fold(
                 HashMap::with_capacity(actions.len()),
                 |mut acc, (i, e)| {
@@ -82,6 +223,7 @@ impl Layer {
             )?,
             overrides: Default::default(),
             keymap: Default::default(),
+            transparent: Default::default(),
             index: index,
         })
     }
@@ -90,7 +232,7 @@ impl Layer {
         let mut layers: Vec<_> = self
             .keys
             .values()
-            .flat_map(|k| k.layer_while_held_names())
+            .flat_map(|k| k.overlay_layer_names())
             .filter(|x| *x != self.name)
             .collect();
         layers.dedup();
@@ -99,58 +241,94 @@ impl Layer {
 
     pub fn get_name<'a>(
         params: &'a [Expr<'a>],
-    ) -> Result<(&'a str, &'a str, &'a [Expr<'a>]), String> {
+    ) -> Result<(&'a str, Vec<&'a str>, &'a [Expr<'a>]), String> {
         let [name, params @ ..] = params else {
             return Err("Syntax error".to_string());
         };
-        let (name, parent) = match name {
+        let (name, parents) = match name {
             Atom(x) => match *x {
-                "default" => ("default", "src"),
+                "default" => ("default", vec!["src"]),
                 "src" => return Err("Cannot override src layer".to_string()),
-                name => (name, "default"),
+                name => (name, vec!["default"]),
             },
             List(xs) => {
-                if let [Atom(name), Atom(parent)] = xs.as_slice() {
-                    (*name, *parent)
-                } else {
+                let [Atom(name), parents @ ..] = xs.as_slice() else {
                     return Err("Syntax error".to_string());
+                };
+                if parents.is_empty() {
+                    return Err(format!("Layer {} requires a parent", name));
+                }
+                let parents = parents
+                    .iter()
+                    .map(|p| p.atom())
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(p) = parents.iter().find(|p| **p == *name) {
+                    return Err(format!("Layer {} cannot be its own parent", p));
                 }
+                (*name, parents)
             }
         };
         Ok((
             name,
-            parent,
+            parents,
             match params {
-                [List(x)] => x,
+                // `(rest X)` и `(group name X)` - формы deflayermap, а не список клавиш
+                [List(x)] if !matches!(x.first(), Some(Atom("rest" | "group"))) => x,
                 _ => params,
             },
         ))
     }
-    pub fn from_map(
-        params: &[Expr<'_>],
-        index_by_key: &HashMap<Key, KeyIndex>,
-    ) -> Result<Self, String> {
-        let (name, parent, params) = Self::get_name(params)?;
+    pub fn from_map(params: &[Expr<'_>], source: &Source, groups: &Groups) -> Result<Self, String> {
+        let (name, parents, mut params) = Self::get_name(params)?;
+        let mut keys = HashMap::with_capacity(params.len());
+        let mut rest = None;
+        while let [first, tail @ ..] = params {
+            params = match (first, tail) {
+                (Atom(key), [expr, tail @ ..]) => {
+                    keys.insert(source.find(key)?, Action::from_expr(expr)?);
+                    tail
+                }
+                (List(form), _) => {
+                    match form.as_slice() {
+                        [Atom("rest"), expr] => {
+                            if rest.replace(Action::from_expr(expr)?).is_some() {
+                                return Err(format!("Duplicate rest in {}", name));
+                            }
+                        }
+                        [Atom("group"), Atom(group), expr] => {
+                            let action = Action::from_expr(expr)?;
+                            let group = groups
+                                .get(*group)
+                                .ok_or(format!("Group {} not found", group))?;
+                            for key in group {
+                                let indexes = source.indexes_of(key);
+                                if indexes.is_empty() {
+                                    return Err(format!("Index for {:?} not found", key));
+                                }
+                                for index in indexes {
+                                    keys.insert(index, action.clone());
+                                }
+                            }
+                        }
+                        _ => return Err(format!("Syntax error: {}", first)),
+                    };
+                    tail
+                }
+                _ => return Err("Syntax error".to_string()),
+            };
+        }
+        if let Some(action) = rest {
+            for (index, _) in source.iter() {
+                keys.entry(index).or_insert_with(|| action.clone());
+            }
+        }
         Ok(Layer {
             name: name.to_string(),
-            parent: parent.to_string(),
-            keys: params.chunks(2).into_iter().try_fold(
-                HashMap::with_capacity(params.len()),
-                |mut acc, v| {
-                    let [Atom(key), expr] = v else {
-                        return Err("Syntax error".to_string());
-                    };
-                    let src: Key = key.parse().map_err(|_| format!("Unknown key {:?}", key))?;
-                    let index = index_by_key
-                        .get(&src)
-                        .ok_or(format!("Index for {:?} not found", src))?;
-                    let action = Action::from_expr(expr)?;
-                    acc.insert(*index, action);
-                    Ok(acc)
-                },
-            )?,
+            parents: parents.iter().map(|p| p.to_string()).collect(),
+            keys,
             overrides: Default::default(),
             keymap: Default::default(),
+            transparent: Default::default(),
             index: 0,
         })
     }
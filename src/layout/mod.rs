@@ -1,3 +1,5 @@
+use keys::keys::Key;
+use log::warn;
 use parser::{Keyboard, parse_vial};
 use s_expression::Expr::*;
 use std::{
@@ -6,71 +8,137 @@ use std::{
 };
 
 mod action;
+mod alias;
+mod config;
+mod hands;
 mod layer;
+mod options;
 mod preprocess;
+mod switch;
 mod template;
 mod unicode;
 mod unwrap;
-pub use action::Action;
-pub use layer::{Keymap, Layer, Override};
+mod validate;
+pub mod visit;
+pub use action::{
+    Action, SwitchCase, SwitchCondition, TapHoldFlavor, VirtualKeyAction, VirtualKeyEvent,
+};
+use alias::Aliases;
+pub use config::{Config, Transparency};
+pub use layer::{Groups, Keymap, Layer, Override, OverrideOptions};
 use preprocess::preprocess;
-use unicode::unicode;
+pub use switch::LangSwitch;
+use unicode::Resolver;
+use visit::ActionFold;
 
 #[derive(Debug, Default)]
 pub struct Layout {
     pub layers: HashMap<String, Layer>,
     pub keyboard: Keyboard,
-    pub keymaps: HashMap<Keymap, Action>,
+    pub switch: LangSwitch,
+    pub groups: Groups,
+    pub hands: hands::Hands,
+    pub virtual_keys: Vec<(String, Action)>,
+    pub alt_repeat: Vec<(Key, Action)>,
+    pub config: Config,
+    pub unicode: unicode::Tables,
+    pub imported: unicode::Tables,
 }
 impl Layout {
     fn new() -> Self {
         Self::default()
     }
-    fn prepare_layers(&mut self, aliases: &HashMap<String, Action>) -> Result<(), String> {
+    fn prepare_layers(&mut self, aliases: &Aliases) -> Result<(), String> {
         let layer_names: Vec<String> = self.layers.keys().cloned().collect();
 
+        if let Some(layer) = aliases.layers().find(|l| !self.layers.contains_key(*l)) {
+            return Err(format!("Layer {:?} of defalias not found", layer));
+        }
+        for (_, action) in self.alt_repeat.iter_mut() {
+            *action = action.resolve_aliases(aliases, None)?;
+        }
+        for (_, action) in self.virtual_keys.iter_mut() {
+            *action = action.resolve_aliases(aliases, None)?;
+        }
+
         for name in &layer_names {
             let layer = self
                 .layers
                 .get_mut(name)
                 .ok_or(format!("Layer {:?} not found", name))?;
             for action in layer.keys.values_mut() {
-                *action = action.resolve_aliases(aliases)?;
+                *action = action.resolve_aliases(aliases, Some(name))?;
             }
+            layer.transparent = layer
+                .keys
+                .iter()
+                .filter(|(_, a)| matches!(a, Action::Transparent))
+                .map(|(k, _)| *k)
+                .collect();
         }
 
+        let actions = self
+            .layers
+            .values()
+            .flat_map(|l| l.keys.values())
+            .chain(self.virtual_keys.iter().map(|(_, a)| a));
+        for action in actions {
+            for name in action.virtual_key_names() {
+                if !self.virtual_keys.iter().any(|(n, _)| n == name) {
+                    return Err(format!("Virtual key {} not defined", name));
+                }
+            }
+        }
+
+        let derived;
+        let hands = if self.hands.is_empty() {
+            derived = hands::Hands::from_matrix(&self.keyboard.matrix);
+            &derived
+        } else {
+            &self.hands
+        };
+        let source = &self.keyboard.source;
+        self.layers.values_mut().try_for_each(|layer| {
+            layer.keys.iter_mut().try_for_each(|(index, action)| {
+                if let Action::TapHold(_, _, config) = action
+                    && let TapHoldFlavor::OppositeHand(keys) = &mut config.flavor
+                {
+                    let position = source
+                        .get(index)
+                        .ok_or(format!("Key {:?} not found", index))?;
+                    *keys = hands.same_hand(&position.key, source)?;
+                }
+                Ok::<_, String>(())
+            })
+        })?;
+
+        // Замены считаются до применения, иначе родитель скроет следующих
+        let mut updates = vec![];
         for name in &layer_names {
             let layer = self
                 .layers
                 .get(name)
                 .ok_or(format!("Layer {:?} not found", name))?;
-            let parent_name = layer.parent.clone();
-            let updates: Vec<_> = layer
+            let ancestors = self.ancestors(name);
+            layer
                 .keys
                 .iter()
-                .filter_map(|(&key, action)| {
-                    if !matches!(action, Action::Transparent) {
-                        return None;
-                    }
-
-                    let mut current = parent_name.as_str();
-
-                    loop {
-                        let parent = self.layers.get(current)?;
-                        match parent.keys.get(&key) {
-                            Some(a) if !matches!(a, Action::Transparent) => {
-                                break Some((key, a.clone()));
-                            }
-                            _ => current = &parent.parent,
+                .filter(|(_, action)| matches!(action, Action::Transparent))
+                .for_each(|(&key, _)| {
+                    let action = ancestors.iter().find_map(|parent| {
+                        match self.layers.get(*parent)?.keys.get(&key) {
+                            Some(a) if !matches!(a, Action::Transparent) => Some(a.clone()),
+                            _ => None,
                         }
+                    });
+                    if let Some(action) = action {
+                        updates.push((name, key, action));
                     }
-                })
-                .collect();
-
+                });
+        }
+        for (name, key, action) in updates {
             if let Some(layer) = self.layers.get_mut(name) {
-                for (key, action) in updates {
-                    layer.keys.insert(key, action);
-                }
+                layer.keys.insert(key, action);
             }
         }
         self.layers.remove("src");
@@ -88,18 +156,15 @@ impl Layout {
                         if dep.keymap == l.keymap {
                             return None;
                         }
-                        if dep.keys.iter().all(|(_, v)| match l.keymap {
-                            Keymap::En => false,
-                            Keymap::Ru => !v.contains_unicode(),
-                        }) {
+                        if dep.keys.values().all(|v| !v.contains_unicode()) {
                             return None;
                         }
                         let mut new = dep.clone();
-                        let new_name = format!("{}-{:?}", dep.name, l.keymap).to_lowercase();
+                        let new_name = format!("{}-{}", dep.name, l.keymap);
                         new.name = new_name.clone();
 
                         new.keys.values_mut().for_each(|v| {
-                            v.map_layer_while_held(&|x| (x == *name).then(|| new_name.clone()));
+                            v.map_layer_names(&|x| (x == *name).then(|| new_name.clone()));
                         });
 
                         new.keymap = l.keymap.clone();
@@ -111,10 +176,8 @@ impl Layout {
                 if new.len() > 0 {
                     let mut s = l.clone();
                     s.keys.values_mut().for_each(|v| {
-                        v.map_layer_while_held(&|x| {
-                            copies
-                                .contains(x)
-                                .then(|| format!("{}-{:?}", x, l.keymap).to_lowercase())
+                        v.map_layer_names(&|x| {
+                            copies.contains(x).then(|| format!("{}-{}", x, l.keymap))
                         });
                     });
                     new.push(s);
@@ -127,14 +190,27 @@ impl Layout {
             self.layers.insert(l.name.to_string(), l);
         });
 
+        let tables = unicode::merge(&self.imported, &self.unicode)?;
+        let resolver = Resolver {
+            tables: &tables,
+            switch: &self.switch,
+            input: self.config.unicode_input,
+        };
+        let keymaps: HashSet<_> = self.layers.values().map(|l| &l.keymap).collect();
+        keymaps.into_iter().for_each(|keymap| {
+            if !resolver.has_table(keymap) {
+                warn!("No defunicode table for keymap {}", keymap);
+            }
+        });
+
         self.layers.values_mut().try_for_each(|layer| {
             layer.keys.values_mut().try_for_each(|action| {
-                resolve_unicode(action, &layer.keymap, &self.keymaps).map(|a| {
+                resolve_unicode(action, &layer.keymap, &resolver).map(|a| {
                     *action = a;
                 })
             })?;
             layer.overrides.iter_mut().try_for_each(|o| {
-                resolve_unicode(&o.action, &layer.keymap, &self.keymaps).map(|a| {
+                resolve_unicode(&o.action, &layer.keymap, &resolver).map(|a| {
                     o.action = a.clone();
                 })
             })?;
@@ -143,20 +219,51 @@ impl Layout {
 
         Ok(())
     }
-    fn layer_from(&self, parent: String, name: String, i: usize) -> Result<Layer, String> {
-        let Some(parent) = self
-            .layers
-            .get(&name)
-            .or_else(|| self.layers.get(&parent))
-            .or_else(|| self.layers.get(&"src".to_string()))
-        else {
-            return Err(format!("Layer {:?} not defined", parent));
-        };
-        if parent.name == name {
-            Ok(parent.clone())
-        } else {
-            Ok(parent.child(name, i))
+    fn ancestors(&self, name: &str) -> Vec<&str> {
+        fn visit<'a: 'b, 'b>(
+            layout: &'a Layout,
+            name: &str,
+            path: &mut Vec<&'b str>,
+            out: &mut Vec<&'a str>,
+        ) {
+            let Some(layer) = layout.layers.get(name) else {
+                return;
+            };
+            for parent in &layer.parents {
+                if path.contains(&parent.as_str()) {
+                    continue;
+                }
+                out.push(parent);
+                path.push(parent);
+                visit(layout, parent, path, out);
+                path.pop();
+            }
+        }
+        let mut all = vec![];
+        visit(self, name, &mut vec![name], &mut all);
+        let mut result: Vec<&str> = vec![];
+        for (i, layer) in all.iter().enumerate() {
+            if !all[i + 1..].contains(layer) {
+                result.push(layer);
+            }
         }
+        result
+    }
+    fn layer_from(&self, parents: Vec<String>, name: String, i: usize) -> Result<Layer, String> {
+        if let Some(layer) = self.layers.get(&name) {
+            return Ok(layer.clone());
+        }
+        let mut found: Vec<&Layer> = parents.iter().filter_map(|p| self.layers.get(p)).collect();
+        if found.is_empty() {
+            let src = self
+                .layers
+                .get("src")
+                .ok_or(format!("Layer {:?} not defined", parents.join(" ")))?;
+            found.push(src);
+        }
+        let mut layer = Layer::child(&found, name, i);
+        layer.parents = parents;
+        Ok(layer)
     }
 }
 
@@ -164,11 +271,12 @@ impl FromStr for Layout {
     type Err = String;
     fn from_str(content: &str) -> Result<Self, Self::Err> {
         let content = format!("({})", content);
-        let expr = s_expression::from_str(content.as_str()).map_err(|_| "Parse error")?;
+        let expr =
+            s_expression::from_str(content.as_str()).map_err(|e| e.describe(&content[1..]))?;
         let mut layout = Self::new();
 
         let root = preprocess(&expr)?;
-        let mut aliases: HashMap<String, Action> = HashMap::new();
+        let mut aliases = Aliases::default();
         root.list()?
             .iter()
             .enumerate()
@@ -178,9 +286,7 @@ impl FromStr for Layout {
                 };
                 match name.atom()? {
                     "defsrc" => {
-                        let keymap = parser::parse_keymap(&params.to_vec())?;
-                        let mut km: Vec<_> = keymap.iter().collect();
-                        km.sort_by_key(|(_, i)| *i);
+                        let keymap = parser::parse_keymap(params)?;
                         let src = layout.keyboard.source.len();
                         let dst = keymap.len();
                         if src != 0 && src != dst {
@@ -200,6 +306,9 @@ impl FromStr for Layout {
                         layout.layers.insert(src.name.to_string(), src);
                     }
                     "deflayer" => {
+                        if layout.config.check_rows {
+                            validate::check_rows(&root, params, &content[1..])?;
+                        }
                         let layer = Layer::from_def(params, i)?;
                         let keys = &layout.keyboard.source;
                         if layer.keys.len() != keys.len() {
@@ -213,66 +322,56 @@ impl FromStr for Layout {
                         layout.layers.insert(layer.name.to_string(), layer);
                     }
                     "deflayermap" => {
-                        let layer = Layer::from_map(params, &layout.keyboard.source)?;
-                        let mut l = layout.layer_from(layer.parent, layer.name, i)?;
+                        let layer =
+                            Layer::from_map(params, &layout.keyboard.source, &layout.groups)?;
+                        let mut l = layout.layer_from(layer.parents, layer.name, i)?;
                         l.keys.extend(layer.keys);
                         layout.layers.insert(l.name.to_string(), l);
                     }
-                    "defalias" => {
-                        aliases.extend(
-                            params
-                                .chunks(2)
-                                .map(|x| {
-                                    let [Atom(name), expr] = x else {
-                                        return Err(format!("Syntax error: {:?}", x));
-                                    };
-                                    let action = Action::from_expr(expr)?;
-                                    Ok((name.to_string(), action))
-                                })
-                                .collect::<Result<Vec<_>, _>>()?,
-                        );
-                    }
+                    "defalias" => aliases.parse(params)?,
                     "defkeymap" => {
-                        params.chunks(3).try_for_each(|x| {
-                            let [Atom(layer), Atom(keymap), act] = x else {
-                                return Err(format!("Syntax error: {:?}", x));
-                            };
-                            let layer = layout
-                                .layers
-                                .get_mut(*layer)
-                                .ok_or(format!("Layer {:?} not found", layer))?;
-
-                            let keymap: Keymap = keymap
-                                .parse()
-                                .map_err(|_| format!("Unknown keymap {:?}", keymap))?;
-
-                            let action = Action::from_expr(act)?;
-
-                            layout.keymaps.insert(keymap.clone(), action);
-                            layer.keymap = keymap.clone();
-
-                            Ok(())
-                        })?;
+                        layout.switch.parse(params)?.into_iter().try_for_each(
+                            |(layer, keymap)| {
+                                layout
+                                    .layers
+                                    .get_mut(layer)
+                                    .ok_or(format!("Layer {:?} not found", layer))?
+                                    .keymap = keymap;
+                                Ok::<_, String>(())
+                            },
+                        )?;
                     }
                     "defoverride" => {
-                        let (name, parent, params) = Layer::get_name(params)?;
-                        let mut layer =
-                            layout.layer_from(parent.to_string(), name.to_string(), i)?;
+                        let (name, parents, params) = Layer::get_name(params)?;
+                        let parents = parents.iter().map(|p| p.to_string()).collect();
+                        let mut layer = layout.layer_from(parents, name.to_string(), i)?;
 
-                        layer.overrides = params
-                            .chunks(2)
+                        layer.overrides = layer::override_entries(params)?
+                            .into_iter()
                             .map(|x| {
-                                let [Atom(src), expr] = x else {
+                                let [Atom(src), expr, options @ ..] = x else {
                                     return Err(format!("Syntax error: {:?}", x));
                                 };
-                                let Action::Multi(src) = Action::from_expr(&Atom(*src))? else {
+                                if !src.contains('-') || src.starts_with('-') || src.ends_with('-')
+                                {
                                     return Err(format!("Expected hotkey, found {:?}", src));
-                                };
-                                let src: Vec<_> = src
-                                    .into_iter()
-                                    .map(|action| match action {
-                                        Action::Tap(key) => Ok(key),
-                                        x => Err(format!("Expected tap, found {:?}", x)),
+                                }
+                                // Override срабатывает по коду клавиши, а не по позиции
+                                let source = &layout.keyboard.source;
+                                let src: Vec<Key> = src
+                                    .split('-')
+                                    .map(|k| match source.label(k) {
+                                        Some((_, p)) if source.indexes_of(&p.key).len() > 1 => {
+                                            Err(format!(
+                                                "Override can't target position {:?}: \
+                                                {:?} is on several positions",
+                                                k, p.key
+                                            ))
+                                        }
+                                        Some((_, p)) => Ok(p.key),
+                                        None => {
+                                            k.parse().map_err(|_| format!("Unknown key {:?}", k))
+                                        }
                                     })
                                     .collect::<Result<_, _>>()?;
 
@@ -291,47 +390,95 @@ impl FromStr for Layout {
                                     key: key.clone(),
                                     action: Action::from_expr(expr)?,
                                     mods: mods.to_vec(),
+                                    options: layer::OverrideOptions::parse(options)?,
                                 })
                             })
                             .collect::<Result<_, _>>()?;
                         layout.layers.insert(layer.name.to_string(), layer);
                     }
+                    "defvirtualkeys" => {
+                        if !params.len().is_multiple_of(2) {
+                            return Err(format!("Syntax error: {:?}", params));
+                        }
+                        params.chunks(2).try_for_each(|x| {
+                            let [Atom(name), expr] = x else {
+                                return Err(format!("Syntax error: {:?}", x));
+                            };
+                            if layout.virtual_keys.iter().any(|(n, _)| n == name) {
+                                return Err(format!("Virtual key {} already defined", name));
+                            }
+                            let action = Action::from_expr(expr)?;
+                            layout.virtual_keys.push((name.to_string(), action));
+                            Ok(())
+                        })?;
+                    }
+                    "defhands" => layout.hands.parse(params)?,
+                    "defgroup" => layer::parse_groups(params, &mut layout.groups)?,
                     "defvial" => layout.keyboard.vial = parse_vial(params)?,
+                    "defcfg" => layout.config.parse(params)?,
+                    "defaltrepeat" => {
+                        if !params.len().is_multiple_of(2) {
+                            return Err(format!("Syntax error: {:?}", params));
+                        }
+                        params.chunks(2).try_for_each(|x| {
+                            let [Atom(key), expr] = x else {
+                                return Err(format!("Syntax error: {:?}", x));
+                            };
+                            let key: Key =
+                                key.parse().map_err(|_| format!("Unknown key {:?}", key))?;
+                            let action = Action::from_expr(expr)?;
+                            match layout.alt_repeat.iter_mut().find(|(k, _)| *k == key) {
+                                Some(pair) => pair.1 = action,
+                                None => layout.alt_repeat.push((key, action)),
+                            }
+                            Ok(())
+                        })?;
+                    }
+                    "defunicode" => {
+                        let (keymap, table) = unicode::parse_defunicode(params)?;
+                        unicode::extend(&mut layout.unicode, keymap, table)?;
+                    }
+                    "importxkb" => {
+                        let (keymap, table) = unicode::import_xkb(params)?;
+                        layout.imported.entry(keymap).or_default().extend(table);
+                    }
 
                     _ => return Err(format!("Unexpected {}", name)),
                 }
                 Ok(())
             })?;
+        validate::check_layers(&layout, &root, &content[1..])?;
         layout.prepare_layers(&aliases)?;
         Ok(layout)
     }
 }
 
-fn resolve_unicode(
-    action: &Action,
-    lang: &Keymap,
-    keymaps: &HashMap<Keymap, Action>,
-) -> Result<Action, String> {
-    Ok(match action {
-        Action::Unicode(ch) => unicode(ch, lang, keymaps)?,
-        Action::TapHold(tap, hold) => Action::TapHold(
-            Box::new(resolve_unicode(tap, lang, keymaps)?),
-            Box::new(resolve_unicode(hold, lang, keymaps)?),
-        ),
-        Action::Multi(actions) => Action::Multi(
-            actions
-                .iter()
-                .map(|a| resolve_unicode(a, lang, keymaps))
-                .collect::<Result<_, _>>()?,
-        ),
-        Action::Sequence(actions) => Action::Sequence(
-            actions
-                .iter()
-                .map(|a| resolve_unicode(a, lang, keymaps))
-                .collect::<Result<_, _>>()?,
-        ),
-        other => other.clone(),
-    })
+struct ResolveUnicode<'a> {
+    lang: &'a Keymap,
+    resolver: &'a Resolver<'a>,
+}
+
+impl ActionFold for ResolveUnicode<'_> {
+    type Error = String;
+    fn fold(&mut self, action: Action) -> Result<Action, String> {
+        Ok(match action {
+            Action::Unicode(ch) => self.resolver.resolve(&ch, self.lang)?,
+            Action::Sequence(actions) => Action::Sequence(
+                actions
+                    .into_iter()
+                    .flat_map(|a| match a {
+                        Action::Sequence(inner) => inner,
+                        a => vec![a],
+                    })
+                    .collect(),
+            ),
+            a => a,
+        })
+    }
+}
+
+fn resolve_unicode(action: &Action, lang: &Keymap, resolver: &Resolver) -> Result<Action, String> {
+    action.clone().fold(&mut ResolveUnicode { lang, resolver })
 }
 
 fn check_all_with<T, F>(src: &[T], predicate: F) -> Result<(), &T>
@@ -0,0 +1,103 @@
+use s_expression::Expr::{self, *};
+use std::{collections::HashMap, str::FromStr};
+
+#[derive(Debug, Default)]
+pub struct Options<'a, 'b>(HashMap<&'a str, &'b Expr<'a>>);
+
+pub fn split_options<'a, 'b>(
+    params: &'b [Expr<'a>],
+) -> Result<(&'b [Expr<'a>], Options<'a, 'b>), String> {
+    let start = params
+        .iter()
+        .position(|p| matches!(p, Atom(x) if x.starts_with(":") && x.len() > 1))
+        .unwrap_or(params.len());
+    let (positional, rest) = params.split_at(start);
+    if !rest.len().is_multiple_of(2) {
+        return Err(format!("Option {} requires a value", rest[rest.len() - 1]));
+    }
+    let mut options = HashMap::with_capacity(rest.len() / 2);
+    rest.chunks(2).try_for_each(|x| {
+        let [Atom(name), value] = x else {
+            return Err(format!("Syntax error: {:?}", x));
+        };
+        let Some(name) = name.strip_prefix(":") else {
+            return Err(format!("Expected option, found {:?}", name));
+        };
+        if options.insert(name, value).is_some() {
+            return Err(format!("Option :{} duplicate", name));
+        }
+        Ok(())
+    })?;
+    Ok((positional, Options(options)))
+}
+
+impl<'a, 'b> Options<'a, 'b> {
+    pub fn take(&mut self, name: &str) -> Option<&'b Expr<'a>> {
+        self.0.remove(name)
+    }
+    pub fn take_atom(&mut self, name: &str) -> Result<Option<&'a str>, String> {
+        self.take(name).map(|e| e.atom()).transpose()
+    }
+    pub fn take_parsed<T: FromStr>(&mut self, name: &str) -> Result<Option<T>, String> {
+        self.take_atom(name)?
+            .map(|x| {
+                x.parse()
+                    .map_err(|_| format!("Invalid value {:?} for :{}", x, name))
+            })
+            .transpose()
+    }
+    pub fn take_bool(&mut self, name: &str) -> Result<Option<bool>, String> {
+        self.take_atom(name)?
+            .map(|x| match x {
+                "yes" => Ok(true),
+                "no" => Ok(false),
+                _ => Err(format!(
+                    "Invalid value {:?} for :{}, expected yes or no",
+                    x, name
+                )),
+            })
+            .transpose()
+    }
+    pub fn take_list(&mut self, name: &str) -> Result<Option<Vec<&'a str>>, String> {
+        self.take(name)
+            .map(|e| match e {
+                Atom(x) => Ok(vec![*x]),
+                List(xs) => xs.iter().map(|x| x.atom()).collect(),
+            })
+            .transpose()
+    }
+    pub fn finish(self) -> Result<(), String> {
+        match self.0.keys().next() {
+            Some(name) => Err(format!("Unknown option :{}", name)),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let expr = s_expression::from_str("(a M :timeout 180 :flavor permissive)").unwrap();
+        let (positional, mut options) = split_options(expr.list().unwrap()).unwrap();
+        assert_eq!(positional, &[Atom("a"), Atom("M")]);
+        assert_eq!(options.take_parsed::<u16>("timeout"), Ok(Some(180)));
+        assert_eq!(options.take_atom("flavor"), Ok(Some("permissive")));
+        assert!(options.finish().is_ok());
+    }
+
+    #[test]
+    fn missing_value() {
+        let expr = s_expression::from_str("(a M :timeout)").unwrap();
+        assert!(split_options(expr.list().unwrap()).is_err());
+    }
+
+    #[test]
+    fn unknown_option() {
+        let expr = s_expression::from_str("(a :foo 1)").unwrap();
+        let (_, options) = split_options(expr.list().unwrap()).unwrap();
+        assert!(options.finish().is_err());
+    }
+}
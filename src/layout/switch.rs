@@ -0,0 +1,195 @@
+use super::{Action, Keymap};
+use s_expression::Expr::{self, *};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone)]
+pub enum LangSwitch {
+    Select(HashMap<Keymap, Action>),
+    Toggle(Action, Vec<Keymap>),
+    Cycle(Action, Vec<Keymap>),
+}
+
+impl Default for LangSwitch {
+    fn default() -> Self {
+        Self::Select(HashMap::new())
+    }
+}
+
+fn parse_keymap(keymap: &str) -> Result<Keymap, String> {
+    keymap
+        .parse()
+        .map_err(|_| format!("Unknown keymap {:?}", keymap))
+}
+
+fn pairs<'a>(params: &[Expr<'a>]) -> Result<Vec<(&'a str, Keymap)>, String> {
+    params
+        .chunks(2)
+        .map(|x| {
+            let [Atom(layer), Atom(keymap)] = x else {
+                return Err(format!("Syntax error: {:?}", x));
+            };
+            Ok((*layer, parse_keymap(keymap)?))
+        })
+        .collect()
+}
+
+impl LangSwitch {
+    pub fn parse<'a>(&mut self, params: &[Expr<'a>]) -> Result<Vec<(&'a str, Keymap)>, String> {
+        let (strategy, params) = match params {
+            [List(strategy), params @ ..] => (Some(strategy.as_slice()), params),
+            _ => (None, params),
+        };
+        match (strategy, &mut *self) {
+            (None, LangSwitch::Select(hotkeys)) => params
+                .chunks(3)
+                .map(|x| {
+                    let [Atom(layer), Atom(keymap), act] = x else {
+                        return Err(format!("Syntax error: {:?}", x));
+                    };
+                    let keymap = parse_keymap(keymap)?;
+                    hotkeys.insert(keymap.clone(), Action::from_expr(act)?);
+                    Ok((*layer, keymap))
+                })
+                .collect(),
+            (Some([Atom("toggle"), hotkey]), _) => {
+                let hotkey = Action::from_expr(hotkey)?;
+                let layers = pairs(params)?;
+                let mut langs = match self {
+                    LangSwitch::Select(hotkeys) if hotkeys.is_empty() => vec![],
+                    LangSwitch::Toggle(h, langs) if *h == hotkey => langs.clone(),
+                    _ => return Err("Keymap switch is already defined".to_string()),
+                };
+                for (_, lang) in &layers {
+                    if !langs.contains(lang) {
+                        langs.push(lang.clone());
+                    }
+                }
+                if langs.len() > 2 {
+                    return Err(format!(
+                        "Toggle switches between two keymaps, found {}, use cycle or select",
+                        langs.len()
+                    ));
+                }
+                *self = LangSwitch::Toggle(hotkey, langs);
+                Ok(layers)
+            }
+            (Some([Atom("cycle"), hotkey, langs @ ..]), _) => {
+                let hotkey = Action::from_expr(hotkey)?;
+                let langs = langs
+                    .iter()
+                    .map(|l| parse_keymap(l.atom()?))
+                    .collect::<Result<Vec<_>, _>>()?;
+                match self {
+                    LangSwitch::Select(hotkeys) if hotkeys.is_empty() => {}
+                    LangSwitch::Cycle(h, l) if *h == hotkey && *l == langs => {}
+                    _ => return Err("Keymap switch is already defined".to_string()),
+                }
+                let layers = pairs(params)?;
+                if let Some((_, lang)) = layers.iter().find(|(_, l)| !langs.contains(l)) {
+                    return Err(format!("Keymap {} is not in the cycle", lang));
+                }
+                *self = LangSwitch::Cycle(hotkey, langs);
+                Ok(layers)
+            }
+            (None, _) => Err("Keymap switch is already defined".to_string()),
+            (Some(strategy), _) => Err(format!("Unknown keymap switch {:?}", strategy)),
+        }
+    }
+
+    pub fn languages(&self) -> Vec<Keymap> {
+        match self {
+            LangSwitch::Select(hotkeys) => {
+                let mut langs: Vec<_> = hotkeys.keys().cloned().collect();
+                langs.sort();
+                langs
+            }
+            LangSwitch::Toggle(_, langs) | LangSwitch::Cycle(_, langs) => langs.clone(),
+        }
+    }
+
+    pub fn sequence(&self, from: &Keymap, to: &Keymap) -> Result<Vec<Action>, String> {
+        if from == to {
+            return Ok(vec![]);
+        }
+        match self {
+            LangSwitch::Select(hotkeys) => hotkeys
+                .get(to)
+                .map(|a| vec![a.clone()])
+                .ok_or(format!("No hotkey for keymap {}", to)),
+            LangSwitch::Toggle(hotkey, langs) => {
+                if langs.contains(from) && langs.contains(to) {
+                    Ok(vec![hotkey.clone()])
+                } else {
+                    Err(format!("Cannot toggle from {} to {}", from, to))
+                }
+            }
+            LangSwitch::Cycle(hotkey, langs) => {
+                let position = |lang| {
+                    langs
+                        .iter()
+                        .position(|l| l == lang)
+                        .ok_or(format!("Keymap {} is not in the cycle", lang))
+                };
+                let steps = (position(to)? + langs.len() - position(from)?) % langs.len();
+                Ok(vec![hotkey.clone(); steps])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::keys::Key;
+
+    fn parse(switch: &mut LangSwitch, input: &str) -> Result<Vec<String>, String> {
+        let expr = s_expression::from_str(input).unwrap();
+        let layers = switch.parse(expr.list()?)?;
+        Ok(layers.iter().map(|(l, k)| format!("{} {}", l, k)).collect())
+    }
+
+    fn keymap(name: &str) -> Keymap {
+        name.parse().unwrap()
+    }
+
+    #[test]
+    fn select() {
+        let mut switch = LangSwitch::default();
+        let layers = parse(&mut switch, "(default en S-A-8 nav ru S-A-9)").unwrap();
+        assert_eq!(layers, vec!["default en", "nav ru"]);
+        assert_eq!(
+            switch.sequence(&keymap("en"), &keymap("ru")).unwrap().len(),
+            1
+        );
+        assert!(switch.sequence(&keymap("en"), &keymap("uk")).is_err());
+        assert!(parse(&mut switch, "((toggle M-spc) default en)").is_err());
+    }
+
+    #[test]
+    fn toggle() {
+        let mut switch = LangSwitch::default();
+        parse(&mut switch, "((toggle caps) default en ru ru)").unwrap();
+        assert_eq!(
+            switch.sequence(&keymap("ru"), &keymap("en")),
+            Ok(vec![Action::Tap(Key::CapsLock)])
+        );
+        assert!(parse(&mut switch, "((toggle caps) uk uk)").is_err());
+    }
+
+    #[test]
+    fn cycle() {
+        let mut switch = LangSwitch::default();
+        parse(&mut switch, "((cycle caps en ru uk) default en ru ru)").unwrap();
+        let caps = Action::Tap(Key::CapsLock);
+        assert_eq!(
+            switch.sequence(&keymap("en"), &keymap("uk")),
+            Ok(vec![caps.clone(), caps.clone()])
+        );
+        assert_eq!(
+            switch.sequence(&keymap("uk"), &keymap("en")),
+            Ok(vec![caps])
+        );
+        assert!(parse(&mut switch, "((cycle caps en ru uk) nav de)").is_err());
+        assert!(parse(&mut switch, "((cycle caps en ru) nav en)").is_err());
+    }
+}
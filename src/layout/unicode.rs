@@ -1,20 +1,94 @@
-use super::{Action, Keymap};
-use s_expression::Expr::*;
-use std::collections::HashMap;
+use super::{Action, Keymap, LangSwitch, config::UnicodeInput};
+use keys::keys::Key;
+use log::warn;
+use s_expression::Expr::{self, *};
+use std::{collections::HashMap, path::Path};
 
 use std::sync::OnceLock;
 
-static LANG_CHARS: OnceLock<Result<HashMap<Keymap, HashMap<char, Action>>, String>> =
-    OnceLock::new();
+#[derive(Debug, Clone, PartialEq)]
+pub enum Entry {
+    Action(Action),
+    Dead(Action),
+    Compose(Vec<Action>),
+}
+
+pub type Table = HashMap<char, Entry>;
+pub type Tables = HashMap<Keymap, Table>;
+
+static BUNDLED: OnceLock<Result<Tables, String>> = OnceLock::new();
+
+pub fn parse_defunicode(params: &[Expr]) -> Result<(Keymap, Table), String> {
+    let [Atom(keymap), params @ ..] = params else {
+        return Err(format!("Expected atom, found {:?}", params));
+    };
+
+    let keymap = keymap
+        .parse::<Keymap>()
+        .map_err(|_| format!("Invalid keymap name {:?}", keymap))?;
+
+    if params.len() % 2 != 0 {
+        return Err("Syntax error".to_string());
+    }
+
+    let table =
+        params
+            .chunks(2)
+            .try_fold(HashMap::with_capacity(params.len() / 2), |mut acc, c| {
+                let [Atom(ch), action] = c else {
+                    return Err(format!("Syntax error: {:?}", c));
+                };
+
+                let ch = parse_char(ch).ok_or(format!("Expected char, found {:?}", ch))?;
+                let entry = parse_entry(action)?;
+                if acc.insert(ch, entry).is_some() {
+                    return Err(format!("Character {:?} duplicate in {} table", ch, keymap));
+                }
+                Ok::<_, String>(acc)
+            })?;
+    Ok((keymap, table))
+}
+
+fn parse_char(s: &str) -> Option<char> {
+    let mut chars = s.chars();
+    match (s, chars.next(), chars.next()) {
+        ("lb", _, _) => Some('('),
+        ("rb", _, _) => Some(')'),
+        (_, Some(c), None) => Some(c),
+        _ => None,
+    }
+}
 
-fn load_lang_chars() -> Result<HashMap<Keymap, HashMap<char, Action>>, String> {
+fn parse_entry(expr: &Expr) -> Result<Entry, String> {
+    Ok(match expr {
+        List(list) => match list.as_slice() {
+            [Atom("dead"), action] => Entry::Dead(Action::from_expr(action)?),
+            [Atom("compose"), steps @ ..] if !steps.is_empty() => Entry::Compose(
+                steps
+                    .iter()
+                    .map(|step| match step {
+                        Atom(x) => match parse_char(x) {
+                            Some(c) => Ok(Action::Unicode(c)),
+                            None => Action::from_expr(step),
+                        },
+                        _ => Action::from_expr(step),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            [Atom("dead" | "compose"), ..] => return Err(format!("Syntax error: {}", expr)),
+            _ => Entry::Action(Action::from_expr(expr)?),
+        },
+        _ => Entry::Action(Action::from_expr(expr)?),
+    })
+}
+
+fn load_bundled() -> Result<Tables, String> {
     let content = format!("({})", include_str!("unicode.rkl"));
     let expr = s_expression::from_str(&content).map_err(|_| "Parse error")?;
     let list = expr.list()?;
 
-    list.iter().try_fold(
-        HashMap::<Keymap, HashMap<char, Action>>::with_capacity(list.len()),
-        |mut acc, l| {
+    list.iter()
+        .try_fold(Tables::with_capacity(list.len()), |mut acc, l| {
             let list = l.list()?;
             let [Atom(name), params @ ..] = list.as_slice() else {
                 return Err(format!("Name of {} not found", l));
@@ -22,76 +96,446 @@ fn load_lang_chars() -> Result<HashMap<Keymap, HashMap<char, Action>>, String> {
             if *name != "defunicode" {
                 return Err(format!("Unknown {:?}", name));
             }
+            let (keymap, table) = parse_defunicode(params)?;
+            acc.entry(keymap).or_default().extend(table);
+            Ok(acc)
+        })
+}
 
-            let [Atom(keymap), params @ ..] = params else {
-                return Err(format!("Expected atom, found {:?}", params));
-            };
+pub fn extend(tables: &mut Tables, keymap: Keymap, table: Table) -> Result<(), String> {
+    let current = tables.entry(keymap.clone()).or_default();
+    for (ch, action) in table {
+        match current.get(&ch) {
+            Some(a) if *a != action => {
+                return Err(format!(
+                    "Character {:?} in {} defined twice: {:?} and {:?}",
+                    ch, keymap, a, action
+                ));
+            }
+            _ => {}
+        }
+        if let Some((other, _)) = current.iter().find(|(c, a)| **c != ch && **a == action) {
+            return Err(format!(
+                "Characters {:?} and {:?} in {} are both typed with {:?}",
+                other, ch, keymap, action
+            ));
+        }
+        current.insert(ch, action);
+    }
+    Ok(())
+}
 
-            let keymap = keymap
-                .parse::<Keymap>()
-                .map_err(|_| format!("Keymap {:?} not found", keymap))?;
+fn apply(tables: &mut Tables, overrides: &Tables) {
+    for (keymap, chars) in overrides {
+        let table = tables.entry(keymap.clone()).or_default();
+        for (ch, action) in chars {
+            let shadowed: Vec<char> = table
+                .iter()
+                .filter(|(c, a)| *c != ch && *a == action && !chars.contains_key(c))
+                .map(|(c, _)| *c)
+                .collect();
+            for c in shadowed {
+                warn!(
+                    "{:?} in {} is typed with {:?}, replaced by {:?}",
+                    c, keymap, action, ch
+                );
+                table.remove(&c);
+            }
+            table.insert(*ch, action.clone());
+        }
+    }
+}
+
+pub fn merge(imported: &Tables, user: &Tables) -> Result<Tables, String> {
+    let mut tables = BUNDLED
+        .get_or_init(load_bundled)
+        .as_ref()
+        .map_err(|e| e.clone())?
+        .clone();
+    apply(&mut tables, imported);
+    apply(&mut tables, user);
+    Ok(tables)
+}
+
+fn unquote(s: &str) -> Result<&str, String> {
+    s.strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or(format!("Expected string, found {}", s))
+}
+
+pub fn import_xkb(params: &[Expr]) -> Result<(Keymap, Table), String> {
+    let (keymap, path, variant) = match params {
+        [Atom(keymap), Atom(path)] => (keymap, unquote(path)?, None),
+        [Atom(keymap), Atom(path), Atom(variant)] => (keymap, unquote(path)?, Some(*variant)),
+        _ => return Err(format!("Syntax error: {:?}", params)),
+    };
+    let keymap = keymap
+        .parse::<Keymap>()
+        .map_err(|_| format!("Invalid keymap name {:?}", keymap))?;
+
+    let path = Path::new(path);
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let file = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .ok_or(format!("Invalid path {:?}", path))?;
+    let symbols = xkb::parse(file, variant, &|name| {
+        std::fs::read_to_string(dir.join(name)).ok()
+    })?;
+    for skipped in &symbols.skipped {
+        warn!(
+            "{}: include {:?} not found, skipped",
+            path.display(),
+            skipped
+        );
+    }
 
-            if params.len() % 2 != 0 {
-                return Err("Syntax error".to_string());
+    let mut table = Table::new();
+    for level in 0..4 {
+        for (key, levels) in &symbols.keys {
+            let Some(keysym) = levels.get(level) else {
+                continue;
+            };
+            let action = match level {
+                0 => Action::Tap(*key),
+                1 => Action::Multi(vec![Action::Tap(Key::LeftShift), Action::Tap(*key)]),
+                2 => Action::Multi(vec![Action::Tap(Key::RightAlt), Action::Tap(*key)]),
+                _ => Action::Multi(vec![
+                    Action::Tap(Key::LeftShift),
+                    Action::Tap(Key::RightAlt),
+                    Action::Tap(*key),
+                ]),
+            };
+            if let Some(ch) = xkb::keysym_to_char(keysym) {
+                table.entry(ch).or_insert(Entry::Action(action));
+            } else if let Some(ch) = xkb::dead_key(keysym) {
+                table.entry(ch).or_insert(Entry::Dead(action));
             }
+        }
+    }
 
-            acc.insert(
-                keymap.clone(),
-                params.chunks(2).try_fold(
-                    HashMap::with_capacity(params.len() / 2),
-                    |mut acc, c| {
-                        let [Atom(ch), action] = c else {
-                            unreachable!()
-                        };
-
-                        let ch = match *ch {
-                            "lb" => '(',
-                            "rb" => ')',
-                            _ => ch
-                                .chars()
-                                .next()
-                                .ok_or(format!("Expected char, found {:?}", ch))?,
-                        };
-
-                        let action = Action::from_expr(action)?;
-                        acc.insert(ch, action);
-                        Ok::<_, String>(acc)
-                    },
-                )?,
-            );
+    let dead: Vec<char> = table
+        .iter()
+        .filter_map(|(ch, e)| matches!(e, Entry::Dead(_)).then_some(*ch))
+        .collect();
+    for d in dead {
+        for (base, result) in xkb::compositions(d) {
+            if matches!(table.get(&base), Some(Entry::Action(_))) && !table.contains_key(&result) {
+                let steps = vec![Action::Unicode(d), Action::Unicode(base)];
+                table.insert(result, Entry::Compose(steps));
+            }
+        }
+    }
+    Ok((keymap, table))
+}
 
-            Ok(acc)
-        },
-    )
+fn hex_digit(digit: char) -> Result<Action, String> {
+    digit
+        .to_string()
+        .parse()
+        .map(Action::Tap)
+        .map_err(|_| format!("Unknown key {:?}", digit))
 }
 
-pub fn unicode(
-    ch: &char,
-    lang: &Keymap,
-    keymaps: &HashMap<Keymap, Action>,
-) -> Result<Action, String> {
-    let lang_chars = LANG_CHARS
-        .get_or_init(load_lang_chars)
-        .as_ref()
-        .map_err(|e| e.clone())?;
+fn fallback(ch: char, lang: &Keymap, input: UnicodeInput) -> Result<Action, String> {
+    Ok(match input {
+        UnicodeInput::Kanata => Action::Unicode(ch),
+        UnicodeInput::Linux => {
+            let mut actions = vec![Action::Multi(vec![
+                Action::Tap(Key::LeftCtrl),
+                Action::Tap(Key::LeftShift),
+                Action::Tap(Key::U),
+            ])];
+            for digit in format!("{:x}", ch as u32).chars() {
+                actions.push(hex_digit(digit)?);
+            }
+            actions.push(Action::Tap(Key::Space));
+            Action::Sequence(actions)
+        }
+        UnicodeInput::Windows => {
+            let code = ch as u32;
+            if code > 255 {
+                return Err(format!(
+                    "Character {:?} has no Windows Alt code, use unicode-input kanata or linux",
+                    ch
+                ));
+            }
+            // С ведущим нулем код берется из кодовой страницы ANSI
+            let code = format!("0{}", code);
+            let mut actions = vec![Action::Hold(Key::LeftAlt)];
+            actions.extend(code.chars().map(|d| Action::Tap(Key::from_digit(d))));
+            actions.push(Action::Release(Key::LeftAlt));
+            Action::Sequence(actions)
+        }
+        UnicodeInput::Error => {
+            return Err(format!("Character {:?} not found in {} keymap", ch, lang));
+        }
+    })
+}
 
-    if let Some(chars) = lang_chars.get(lang) {
-        if let Some(a) = chars.get(ch) {
-            return Ok(a.clone());
+pub struct Resolver<'a> {
+    pub tables: &'a Tables,
+    pub switch: &'a LangSwitch,
+    pub input: UnicodeInput,
+}
+
+impl Resolver<'_> {
+    pub fn has_table(&self, lang: &Keymap) -> bool {
+        self.tables.contains_key(lang)
+    }
+
+    pub fn resolve(&self, ch: &char, lang: &Keymap) -> Result<Action, String> {
+        self.resolve_in(*ch, lang, 0)
+    }
+
+    fn resolve_in(&self, ch: char, lang: &Keymap, depth: usize) -> Result<Action, String> {
+        if let Some(entry) = self.tables.get(lang).and_then(|t| t.get(&ch)) {
+            return self.entry(entry, lang, depth);
         }
-    };
 
-    if let Some(lang_hotkey) = keymaps.get(lang) {
-        for (lang, action) in keymaps.iter() {
-            if let Some(chars) = lang_chars.get(lang) {
-                if let Some(a) = chars.get(ch) {
-                    return Ok(Action::Sequence(
-                        [action.clone(), a.clone(), lang_hotkey.clone()].to_vec(),
-                    ));
-                }
+        let mut best: Option<Vec<Action>> = None;
+        for other in self.switch.languages() {
+            let Some(entry) = self.tables.get(&other).and_then(|t| t.get(&ch)) else {
+                continue;
+            };
+            let (Ok(to), Ok(back)) = (
+                self.switch.sequence(lang, &other),
+                self.switch.sequence(&other, lang),
+            ) else {
+                continue;
+            };
+            if best
+                .as_ref()
+                .is_some_and(|b| b.len() <= to.len() + back.len() + 1)
+            {
+                continue;
             }
+            let mut actions = to;
+            actions.push(self.entry(entry, &other, depth)?);
+            actions.extend(back);
+            best = Some(actions);
+        }
+        if let Some(actions) = best {
+            return Ok(sequence(actions));
         }
+
+        fallback(ch, lang, self.input)
     }
 
-    Ok(Action::Unicode(*ch))
+    fn entry(&self, entry: &Entry, lang: &Keymap, depth: usize) -> Result<Action, String> {
+        if depth > 8 {
+            return Err(format!("Compose sequence in {} is too deep", lang));
+        }
+        Ok(match entry {
+            Entry::Action(action) => action.clone(),
+            Entry::Dead(action) => sequence(vec![action.clone(), Action::Tap(Key::Space)]),
+            Entry::Compose(steps) => sequence(
+                steps
+                    .iter()
+                    .map(|step| match step {
+                        Action::Unicode(c) => match self.tables.get(lang).and_then(|t| t.get(c)) {
+                            Some(Entry::Dead(action)) => Ok(action.clone()),
+                            Some(entry) => self.entry(entry, lang, depth + 1),
+                            None if c.is_ascii_lowercase() || c.is_ascii_digit() => c
+                                .to_string()
+                                .parse()
+                                .map(Action::Tap)
+                                .map_err(|_| format!("Unknown key {:?}", c)),
+                            None => self.resolve_in(*c, lang, depth + 1),
+                        },
+                        step => Ok(step.clone()),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+        })
+    }
+}
+
+fn sequence(actions: Vec<Action>) -> Action {
+    Action::Sequence(
+        actions
+            .into_iter()
+            .flat_map(|a| match a {
+                Action::Sequence(inner) => inner,
+                a => vec![a],
+            })
+            .collect(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(input: &str) -> (Keymap, Table) {
+        let expr = s_expression::from_str(input).unwrap();
+        parse_defunicode(expr.list().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn conflicts() {
+        let mut tables = Tables::new();
+        let (keymap, t) = table("(de ä ' ö ;)");
+        extend(&mut tables, keymap, t).unwrap();
+
+        let (keymap, t) = table("(de ä ' ü [)");
+        assert!(extend(&mut tables, keymap, t).is_ok());
+
+        let (keymap, t) = table("(de ä [)");
+        assert!(extend(&mut tables, keymap, t).is_err());
+
+        let (keymap, t) = table("(de ß ;)");
+        assert!(extend(&mut tables, keymap, t).is_err());
+    }
+
+    #[test]
+    fn user_overrides_bundled() {
+        let mut user = Tables::new();
+        let (keymap, t) = table("(en é ')");
+        extend(&mut user, keymap.clone(), t).unwrap();
+        let tables = merge(&Tables::new(), &user).unwrap();
+        let en = tables.get(&keymap).unwrap();
+        assert_eq!(
+            en.get(&'é'),
+            Some(&Entry::Action(Action::Tap(Key::Apostrophe)))
+        );
+        assert_eq!(en.get(&'\''), None);
+        assert!(en.contains_key(&'!'));
+    }
+
+    #[test]
+    fn import() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/crates/xkb/fixtures/ru");
+        let input = format!("(ru \"{}\" typewriter)", path);
+        let expr = s_expression::from_str(&input).unwrap();
+        let (_, table) = import_xkb(expr.list().unwrap()).unwrap();
+        assert_eq!(
+            table.get(&'ж'),
+            Some(&Entry::Action(Action::Tap(Key::Semicolon)))
+        );
+        assert_eq!(
+            table.get(&'Ж'),
+            Some(&Entry::Action(Action::Multi(vec![
+                Action::Tap(Key::LeftShift),
+                Action::Tap(Key::Semicolon)
+            ])))
+        );
+        assert_eq!(
+            table.get(&'«'),
+            Some(&Entry::Action(Action::Multi(vec![
+                Action::Tap(Key::RightAlt),
+                Action::Tap(Key::Comma)
+            ])))
+        );
+        assert_eq!(
+            table.get(&'|'),
+            Some(&Entry::Action(Action::Tap(Key::Grave)))
+        );
+    }
+
+    #[test]
+    fn bundled_uk() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/crates/xkb/fixtures/ua");
+        let input = format!("(uk \"{}\" winkeys)", path);
+        let expr = s_expression::from_str(&input).unwrap();
+        let (keymap, imported) = import_xkb(expr.list().unwrap()).unwrap();
+        let bundled = load_bundled().unwrap();
+        let uk = bundled.get(&keymap).unwrap();
+        assert!(uk.contains_key(&'ґ'));
+        for (ch, entry) in uk {
+            assert_eq!(imported.get(ch), Some(entry), "{:?}", ch);
+        }
+    }
+
+    fn resolve(tables: &Tables, ch: char) -> Result<Action, String> {
+        let switch = LangSwitch::default();
+        let resolver = Resolver {
+            tables,
+            switch: &switch,
+            input: UnicodeInput::Error,
+        };
+        resolver.resolve(&ch, &"de".parse().unwrap())
+    }
+
+    #[test]
+    fn dead_keys() {
+        let mut tables = Tables::new();
+        let (keymap, t) = table("(de ´ (dead =) é (compose ´ e) € RA-e)");
+        extend(&mut tables, keymap, t).unwrap();
+
+        assert_eq!(
+            resolve(&tables, '´'),
+            Ok(Action::Sequence(vec![
+                Action::Tap(Key::Equal),
+                Action::Tap(Key::Space)
+            ]))
+        );
+        assert_eq!(
+            resolve(&tables, 'é'),
+            Ok(Action::Sequence(vec![
+                Action::Tap(Key::Equal),
+                Action::Tap(Key::E)
+            ]))
+        );
+        assert_eq!(
+            resolve(&tables, '€'),
+            Ok(Action::Multi(vec![
+                Action::Tap(Key::RightAlt),
+                Action::Tap(Key::E)
+            ]))
+        );
+    }
+
+    #[test]
+    fn import_dead_keys() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/crates/xkb/fixtures/de");
+        let input = format!("(de \"{}\")", path);
+        let expr = s_expression::from_str(&input).unwrap();
+        let (keymap, table) = import_xkb(expr.list().unwrap()).unwrap();
+        let tables = Tables::from([(keymap, table)]);
+
+        let shift_equal = Action::Multi(vec![Action::Tap(Key::LeftShift), Action::Tap(Key::Equal)]);
+        assert_eq!(
+            resolve(&tables, 'è'),
+            Ok(Action::Sequence(vec![shift_equal, Action::Tap(Key::E)]))
+        );
+        assert_eq!(
+            resolve(&tables, 'ê'),
+            Ok(Action::Sequence(vec![
+                Action::Tap(Key::Grave),
+                Action::Tap(Key::E)
+            ]))
+        );
+        assert!(resolve(&tables, 'á').is_err());
+    }
+
+    #[test]
+    fn switch_languages() {
+        let mut tables = Tables::new();
+        let (keymap, t) = table("(ru ж ;)");
+        extend(&mut tables, keymap, t).unwrap();
+        let (keymap, t) = table("(uk ж ;)");
+        extend(&mut tables, keymap, t).unwrap();
+
+        let mut switch = LangSwitch::default();
+        let expr = s_expression::from_str("((cycle caps en ru uk))").unwrap();
+        switch.parse(expr.list().unwrap()).unwrap();
+        let resolver = Resolver {
+            tables: &tables,
+            switch: &switch,
+            input: UnicodeInput::Error,
+        };
+
+        let caps = Action::Tap(Key::CapsLock);
+        let semicolon = Action::Tap(Key::Semicolon);
+        assert_eq!(
+            resolver.resolve(&'ж', &"en".parse().unwrap()),
+            Ok(Action::Sequence(vec![
+                caps.clone(),
+                semicolon.clone(),
+                caps.clone(),
+                caps.clone()
+            ]))
+        );
+    }
 }
@@ -0,0 +1,244 @@
+use super::{Layer, Layout};
+use s_expression::Expr::{self, *};
+use std::collections::HashMap;
+
+const LAYER_REFERENCES: &[&str] = &[
+    "layer-while-held",
+    "layer-switch",
+    "layer-toggle",
+    "layer-to",
+    "layer-one-shot",
+    "layer-tap-toggle",
+    "layer",
+];
+
+fn at(source: &str, token: &str, message: String) -> String {
+    match s_expression::position(source, token) {
+        Some(position) => format!("{}: {}", position, message),
+        None => message,
+    }
+}
+
+pub fn check_layers(layout: &Layout, root: &Expr, source: &str) -> Result<(), String> {
+    let mut parents: HashMap<&str, Vec<&str>> = HashMap::new();
+    let mut order = vec![];
+    for form in root.list()? {
+        let [Atom(head), params @ ..] = form.list()?.as_slice() else {
+            continue;
+        };
+        if !matches!(*head, "deflayer" | "deflayermap" | "defoverride") {
+            continue;
+        }
+        let (name, declared, _) = Layer::get_name(params)?;
+        let explicit = matches!(params.first(), Some(List(_)));
+        for parent in &declared {
+            if explicit && !layout.layers.contains_key(*parent) {
+                return Err(at(
+                    source,
+                    parent,
+                    format!("Parent {:?} of layer {:?} is not defined", parent, name),
+                ));
+            }
+        }
+        if !order.contains(&name) {
+            order.push(name);
+        }
+        let entry = parents.entry(name).or_default();
+        declared
+            .into_iter()
+            .filter(|p| layout.layers.contains_key(*p))
+            .for_each(|p| {
+                if !entry.contains(&p) {
+                    entry.push(p);
+                }
+            });
+    }
+
+    let mut done: Vec<&str> = vec![];
+    for name in order {
+        check_cycle(&parents, name, &mut vec![], &mut done, source)?;
+    }
+
+    check_references(layout, root, source)
+}
+
+fn check_cycle<'a>(
+    parents: &HashMap<&'a str, Vec<&'a str>>,
+    name: &'a str,
+    path: &mut Vec<&'a str>,
+    done: &mut Vec<&'a str>,
+    source: &str,
+) -> Result<(), String> {
+    if done.contains(&name) {
+        return Ok(());
+    }
+    if let Some(start) = path.iter().position(|x| *x == name) {
+        let cycle: Vec<_> = path[start..].iter().chain([&name]).copied().collect();
+        let parent = path.last().copied().unwrap_or(name);
+        let token = parents
+            .get(parent)
+            .and_then(|ps| ps.iter().find(|p| **p == name))
+            .copied()
+            .unwrap_or(name);
+        return Err(at(
+            source,
+            token,
+            format!("Layer parents form a cycle: {}", cycle.join(" -> ")),
+        ));
+    }
+    path.push(name);
+    for parent in parents.get(name).into_iter().flatten() {
+        check_cycle(parents, parent, path, done, source)?;
+    }
+    path.pop();
+    done.push(name);
+    Ok(())
+}
+
+fn check_references(layout: &Layout, expr: &Expr, source: &str) -> Result<(), String> {
+    let List(items) = expr else {
+        return Ok(());
+    };
+    if let [Atom(head), Atom(name)] = items.as_slice()
+        && LAYER_REFERENCES.contains(head)
+        && !layout.layers.contains_key(*name)
+    {
+        return Err(at(
+            source,
+            name,
+            format!("Layer {:?} used in {} is not defined", name, head),
+        ));
+    }
+    items
+        .iter()
+        .try_for_each(|item| check_references(layout, item, source))
+}
+
+fn first_atom<'a>(expr: &Expr<'a>) -> Option<&'a str> {
+    match expr {
+        Atom(x) => Some(x),
+        List(items) => items.first().and_then(first_atom),
+    }
+}
+
+// None, если строки идут не по порядку, например элементы из шаблона
+fn rows<'a, 'b>(items: &'b [Expr<'a>], source: &str) -> Option<Vec<Vec<&'b Expr<'a>>>> {
+    let mut rows: Vec<(usize, Vec<&Expr>)> = vec![];
+    for item in items {
+        let line = s_expression::position(source, first_atom(item)?)?.line;
+        match rows.last_mut() {
+            Some((last, row)) if *last == line => row.push(item),
+            Some((last, _)) if *last > line => return None,
+            _ => rows.push((line, vec![item])),
+        }
+    }
+    Some(rows.into_iter().map(|(_, row)| row).collect())
+}
+
+pub fn check_rows(root: &Expr, params: &[Expr], source: &str) -> Result<(), String> {
+    let defsrc = root.list()?.iter().find_map(|form| match form {
+        List(items) => match items.as_slice() {
+            [Atom("defsrc"), keys @ ..] => Some(keys),
+            _ => None,
+        },
+        Atom(_) => None,
+    });
+    let Some(expected) = defsrc.and_then(|keys| rows(keys, source)) else {
+        return Ok(());
+    };
+    let (name, _, actions) = Layer::get_name(params)?;
+    let Some(found) = rows(actions, source) else {
+        return Ok(());
+    };
+    if expected.len() < 2 || found.len() < 2 {
+        return Ok(());
+    }
+    for (i, (row, src)) in found.iter().zip(&expected).enumerate() {
+        if row.len() != src.len() {
+            return Err(at(
+                source,
+                first_atom(row[0]).unwrap_or(name),
+                format!(
+                    "Layer {:?}: row {} has {} keys, expected {}",
+                    name,
+                    i + 1,
+                    row.len(),
+                    src.len()
+                ),
+            ));
+        }
+    }
+    if found.len() != expected.len() {
+        return Err(at(
+            source,
+            first_atom(&params[0]).unwrap_or(name),
+            format!(
+                "Layer {:?} has {} rows, expected {}",
+                name,
+                found.len(),
+                expected.len()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Layout;
+
+    fn error(layout: &str) -> String {
+        layout.parse::<Layout>().unwrap_err()
+    }
+
+    #[test]
+    fn unterminated_string() {
+        assert_eq!(
+            error("(defsrc a)\n(deflayermap default a (macro \"abc))"),
+            "2:31: Unterminated string"
+        );
+    }
+
+    #[test]
+    fn unknown_parent() {
+        assert_eq!(
+            error("(defsrc a)\n(deflayermap (nav base) a b)"),
+            "2:19: Parent \"base\" of layer \"nav\" is not defined"
+        );
+    }
+
+    #[test]
+    fn cycle() {
+        assert_eq!(
+            error("(defsrc a) (deflayermap (a b) a x) (deflayermap (b a) a y)"),
+            "1:52: Layer parents form a cycle: a -> b -> a"
+        );
+    }
+
+    #[test]
+    fn undefined_layer() {
+        assert_eq!(
+            error("(defsrc a b)\n(deflayermap default\n  a (layer-while-held nav))"),
+            "3:23: Layer \"nav\" used in layer-while-held is not defined"
+        );
+    }
+
+    #[test]
+    fn rows() {
+        let layout = "(defsrc\n  a b c\n  d e f)\n(deflayer default\n  1 2 3\n  4 5\n  6)";
+        assert_eq!(
+            error(layout),
+            "6:3: Layer \"default\": row 2 has 2 keys, expected 3"
+        );
+        assert!(
+            format!("(defcfg check-rows no) {}", layout)
+                .parse::<Layout>()
+                .is_ok()
+        );
+        assert!(
+            "(defsrc\n a b\n c)\n(deflayer default 1 2 3)"
+                .parse::<Layout>()
+                .is_ok()
+        );
+    }
+}
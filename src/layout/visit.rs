@@ -0,0 +1,226 @@
+use super::action::{Action, SwitchCase, SwitchCondition};
+
+pub trait ActionVisitor<'a> {
+    fn visit(&mut self, action: &'a Action);
+    fn visit_condition(&mut self, _condition: &'a SwitchCondition) {}
+}
+
+impl<'a, F: FnMut(&'a Action)> ActionVisitor<'a> for F {
+    fn visit(&mut self, action: &'a Action) {
+        self(action)
+    }
+}
+
+pub trait ActionFold {
+    type Error;
+    fn fold(&mut self, action: Action) -> Result<Action, Self::Error>;
+    fn fold_condition(
+        &mut self,
+        condition: SwitchCondition,
+    ) -> Result<SwitchCondition, Self::Error> {
+        Ok(condition)
+    }
+}
+
+impl Action {
+    pub fn walk<'a>(&'a self, visitor: &mut impl ActionVisitor<'a>) {
+        visitor.visit(self);
+        match self {
+            Action::TapHold(a, b, _) | Action::Shifted(a, b) => {
+                a.walk(visitor);
+                b.walk(visitor);
+            }
+            Action::Multi(actions) | Action::Sequence(actions) => {
+                actions.iter().for_each(|a| a.walk(visitor));
+            }
+            Action::Switch(cases) => cases.iter().for_each(|c| {
+                c.conditions.iter().for_each(|x| x.walk(visitor));
+                c.action.walk(visitor);
+            }),
+            Action::Tap(_)
+            | Action::Transparent
+            | Action::NoAction
+            | Action::Alias(_)
+            | Action::LayerWhileHeld(_)
+            | Action::LayerSwitch(_)
+            | Action::LayerToggle(_)
+            | Action::LayerTo(_)
+            | Action::LayerOneShot(_)
+            | Action::LayerTapToggle(_)
+            | Action::LayerLock
+            | Action::LayerOff
+            | Action::Unicode(_)
+            | Action::Hold(_)
+            | Action::Release(_)
+            | Action::Delay(_)
+            | Action::MoveMouse(..)
+            | Action::MoveMouseAccel(..)
+            | Action::MouseSpeed(_)
+            | Action::Repeat
+            | Action::RepeatAny
+            | Action::AltRepeat
+            | Action::VirtualKey(..) => {}
+        }
+    }
+
+    pub fn fold<F: ActionFold>(self, folder: &mut F) -> Result<Action, F::Error> {
+        let mut fold_all = |actions: Vec<Action>| {
+            actions
+                .into_iter()
+                .map(|a| a.fold(folder))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let action = match self {
+            Action::TapHold(tap, hold, config) => Action::TapHold(
+                Box::new(tap.fold(folder)?),
+                Box::new(hold.fold(folder)?),
+                config,
+            ),
+            Action::Shifted(base, shifted) => Action::Shifted(
+                Box::new(base.fold(folder)?),
+                Box::new(shifted.fold(folder)?),
+            ),
+            Action::Multi(actions) => Action::Multi(fold_all(actions)?),
+            Action::Sequence(actions) => Action::Sequence(fold_all(actions)?),
+            Action::Switch(cases) => Action::Switch(
+                cases
+                    .into_iter()
+                    .map(|c| {
+                        Ok(SwitchCase {
+                            conditions: c
+                                .conditions
+                                .into_iter()
+                                .map(|x| x.fold(folder))
+                                .collect::<Result<_, _>>()?,
+                            action: c.action.fold(folder)?,
+                            fallthrough: c.fallthrough,
+                        })
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            leaf @ (Action::Tap(_)
+            | Action::Transparent
+            | Action::NoAction
+            | Action::Alias(_)
+            | Action::LayerWhileHeld(_)
+            | Action::LayerSwitch(_)
+            | Action::LayerToggle(_)
+            | Action::LayerTo(_)
+            | Action::LayerOneShot(_)
+            | Action::LayerTapToggle(_)
+            | Action::LayerLock
+            | Action::LayerOff
+            | Action::Unicode(_)
+            | Action::Hold(_)
+            | Action::Release(_)
+            | Action::Delay(_)
+            | Action::MoveMouse(..)
+            | Action::MoveMouseAccel(..)
+            | Action::MouseSpeed(_)
+            | Action::Repeat
+            | Action::RepeatAny
+            | Action::AltRepeat
+            | Action::VirtualKey(..)) => leaf,
+        };
+        folder.fold(action)
+    }
+}
+
+impl SwitchCondition {
+    pub fn walk<'a>(&'a self, visitor: &mut impl ActionVisitor<'a>) {
+        visitor.visit_condition(self);
+        match self {
+            SwitchCondition::And(items) | SwitchCondition::Or(items) => {
+                items.iter().for_each(|c| c.walk(visitor))
+            }
+            SwitchCondition::Not(item) => item.walk(visitor),
+            SwitchCondition::Key(_) | SwitchCondition::Layer(_) | SwitchCondition::History(..) => {}
+        }
+    }
+
+    pub fn fold<F: ActionFold>(self, folder: &mut F) -> Result<SwitchCondition, F::Error> {
+        let mut fold_all = |items: Vec<SwitchCondition>| {
+            items
+                .into_iter()
+                .map(|c| c.fold(folder))
+                .collect::<Result<Vec<_>, _>>()
+        };
+        let condition = match self {
+            SwitchCondition::And(items) => SwitchCondition::And(fold_all(items)?),
+            SwitchCondition::Or(items) => SwitchCondition::Or(fold_all(items)?),
+            SwitchCondition::Not(item) => SwitchCondition::Not(Box::new(item.fold(folder)?)),
+            leaf @ (SwitchCondition::Key(_)
+            | SwitchCondition::Layer(_)
+            | SwitchCondition::History(..)) => leaf,
+        };
+        folder.fold_condition(condition)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use keys::keys::Key;
+
+    fn parse(s: &str) -> Action {
+        Action::from_expr(&s_expression::from_str(s).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn walk_all() {
+        let action = parse("(tap-hold (macro a (shifted b c)) (switch ((layer x)) d break))");
+        let mut taps = vec![];
+        action.walk(&mut |a: &Action| {
+            if let Action::Tap(k) = a {
+                taps.push(*k);
+            }
+        });
+        assert_eq!(taps, vec![Key::A, Key::B, Key::C, Key::D]);
+    }
+
+    #[derive(Default)]
+    struct Layers<'a>(Vec<&'a str>);
+    impl<'a> ActionVisitor<'a> for Layers<'a> {
+        fn visit(&mut self, _: &'a Action) {}
+        fn visit_condition(&mut self, condition: &'a SwitchCondition) {
+            if let SwitchCondition::Layer(l) = condition {
+                self.0.push(l);
+            }
+        }
+    }
+
+    #[test]
+    fn walk_conditions() {
+        let action =
+            parse("(switch ((or (layer x) (not (layer y)))) (switch ((layer z)) a break) break)");
+        let mut layers = Layers::default();
+        action.walk(&mut layers);
+        assert_eq!(layers.0, vec!["x", "y", "z"]);
+    }
+
+    struct Rename;
+    impl ActionFold for Rename {
+        type Error = ();
+        fn fold(&mut self, action: Action) -> Result<Action, ()> {
+            Ok(match action {
+                Action::Tap(Key::A) => Action::Tap(Key::B),
+                a => a,
+            })
+        }
+        fn fold_condition(&mut self, condition: SwitchCondition) -> Result<SwitchCondition, ()> {
+            Ok(match condition {
+                SwitchCondition::Layer(_) => SwitchCondition::Layer("y".into()),
+                c => c,
+            })
+        }
+    }
+
+    #[test]
+    fn fold_all() {
+        let action = parse("(macro a (switch ((not (layer x))) a break))");
+        assert_eq!(
+            action.fold(&mut Rename),
+            Ok(parse("(macro b (switch ((not (layer y))) b break))"))
+        );
+    }
+}
@@ -1,7 +1,4 @@
-mod layout;
-mod transform;
-
-use layout::Layout;
+use rkl::layout::Layout;
 
 use argh::FromArgs;
 
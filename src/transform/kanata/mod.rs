@@ -1,8 +1,10 @@
 use std::collections::{HashMap, HashSet};
 
-use super::config;
-use crate::layout::{Action, Layout};
-use keys::keys::Key;
+use crate::layout::{
+    Action, Layout, SwitchCondition, TapHoldFlavor, Transparency, VirtualKeyAction, VirtualKeyEvent,
+};
+use keys::keys::{Key, KeyIndex};
+use log::warn;
 
 fn sorted<'a, K, V, I: Ord, R, F: Fn(&'a K, &'a V) -> (I, R)>(
     map: &'a HashMap<K, V>,
@@ -20,22 +22,53 @@ impl Layout {
     pub fn kanata(&self) -> Result<String, String> {
         let mut lines = vec![];
 
-        let mut source = sorted(&self.keyboard.source, |k, i| (i, k))
-            .into_iter()
-            .map(key_to_kanata)
-            .collect::<Vec<_>>();
-        source.insert(0, "defsrc".into());
-        lines.push(format!("({})", source.join(" ")));
+        if !self.config.kanata.options.is_empty() {
+            lines.push(format!(
+                "(defcfg\n{}\n)",
+                self.config
+                    .kanata
+                    .options
+                    .iter()
+                    .map(|(name, value)| format!("\t{} {}", name, value))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
 
+        if !self.virtual_keys.is_empty() {
+            let keys = self
+                .virtual_keys
+                .iter()
+                .map(|(name, action)| Ok(format!("\t{} {}", name, action_to_kanata(action, self)?)))
+                .collect::<Result<Vec<_>, String>>()?;
+            lines.push(format!("(defvirtualkeys\n{}\n)", keys.join("\n")));
+        }
+
+        // kanata не различает одинаковые клавиши defsrc, остается первая позиция
+        let mut first = HashMap::<Key, KeyIndex>::new();
         let source = self
             .keyboard
             .source
             .iter()
-            .map(|(k, i)| (i, k))
+            .map(|(i, p)| {
+                first.entry(p.key).or_insert(i);
+                (i, p.key)
+            })
             .collect::<HashMap<_, _>>();
 
+        let mut defsrc = self
+            .keyboard
+            .source
+            .iter()
+            .filter(|(i, p)| first.get(&p.key) == Some(i))
+            .map(|(_, p)| key_to_kanata(&p.key))
+            .collect::<Vec<_>>();
+        defsrc.insert(0, "defsrc".into());
+        lines.push(format!("({})", defsrc.join(" ")));
+
         let mut overrides = HashMap::<String, HashSet<String>>::new();
 
+        let runtime = self.runtime_transparent();
         sorted(&self.layers, |_, l| (l.index, l))
             .into_iter()
             .try_for_each(|l| {
@@ -46,9 +79,25 @@ impl Layout {
                         let Some(key) = source.get(i) else {
                             return Some(Err(format!("Key {:?} not found", i)));
                         };
+                        let main = first[key];
+                        if main != *i {
+                            return match l.keys.get(&main) == Some(a) {
+                                true => None,
+                                false => Some(Err(format!(
+                                    "Positions of {:?} have different actions in layer {}, \
+                                    kanata can't tell them apart",
+                                    key, l.name
+                                ))),
+                            };
+                        }
+                        let a = if runtime.contains(&(l.name.as_str(), *i)) {
+                            &Action::Transparent
+                        } else {
+                            a
+                        };
 
                         let key = key_to_kanata(key);
-                        match action_to_kanata(a) {
+                        match action_to_kanata(a, self) {
                             Ok(action) => {
                                 if action != key {
                                     Some(Ok(format!("\t{} {}", key, action)))
@@ -68,26 +117,54 @@ impl Layout {
                 ));
 
                 l.overrides.iter().try_for_each(|o| {
-                    let action = action_to_kanata(&o.action)?;
+                    let action = action_to_kanata(&o.action, self)?;
                     let action = if action.starts_with("(") {
                         action
                     } else {
                         format!("({})", action)
                     };
-                    let res = format!(
-                        "({} {}) {}",
-                        o.mods
-                            .iter()
-                            .map(key_to_kanata)
-                            .collect::<Vec<_>>()
-                            .join(" "),
-                        key_to_kanata(&o.key),
-                        action
-                    );
-                    overrides
-                        .entry(res)
-                        .or_insert_with(|| HashSet::with_capacity(1))
-                        .insert(l.name.clone());
+                    let options = &o.options;
+                    if options.suppressed_mods.is_some()
+                        || !options.activation_trigger_down
+                        || !options.activation_required_mod_down
+                        || !options.activation_negative_mod_up
+                        || options.no_reregister_trigger
+                        || options.no_unregister_on_other_key_down
+                    {
+                        warn!(
+                            "Kanata supports only :negative-mods and :one-mod in overrides, \
+                            other options of {:?} ignored",
+                            o.key
+                        );
+                    }
+                    let negative = options
+                        .negative_mods
+                        .iter()
+                        .map(key_to_kanata)
+                        .collect::<Vec<_>>()
+                        .join(" ");
+                    // Kanata требует все модификаторы, one-mod - по override на каждый
+                    let mods: Vec<Vec<&Key>> = if options.one_mod && !o.mods.is_empty() {
+                        o.mods.iter().map(|m| vec![m]).collect()
+                    } else {
+                        vec![o.mods.iter().collect()]
+                    };
+                    mods.into_iter().for_each(|mods| {
+                        let res = format!(
+                            "({} {}) {} ({})",
+                            mods.into_iter()
+                                .map(key_to_kanata)
+                                .collect::<Vec<_>>()
+                                .join(" "),
+                            key_to_kanata(&o.key),
+                            action,
+                            negative
+                        );
+                        overrides
+                            .entry(res)
+                            .or_insert_with(|| HashSet::with_capacity(1))
+                            .insert(l.name.clone());
+                    });
                     Ok::<_, String>(())
                 })?;
 
@@ -105,7 +182,7 @@ impl Layout {
                             .difference(l)
                             .map(|s| s.clone())
                             .collect::<Vec<_>>();
-                        format!("\t{} () ({})", o, layers.join(" "))
+                        format!("\t{} ({})", o, layers.join(" "))
                     })
                     .collect::<Vec<_>>()
                     .join("\n")
@@ -116,19 +193,103 @@ impl Layout {
     }
 }
 
-fn action_to_kanata(action: &Action) -> Result<String, String> {
+impl Layout {
+    fn runtime_transparent<'a>(&'a self) -> HashSet<(&'a str, KeyIndex)> {
+        if self.config.transparency != Transparency::Runtime {
+            return HashSet::new();
+        }
+        let mut bases: HashSet<&'a str> = HashSet::from(["default"]);
+        self.layers
+            .values()
+            .flat_map(|l| l.keys.values().chain(l.overrides.iter().map(|o| &o.action)))
+            .chain(self.virtual_keys.iter().map(|(_, a)| a))
+            .chain(self.alt_repeat.iter().map(|(_, a)| a))
+            .for_each(|a| {
+                a.walk(&mut |a: &'a Action| {
+                    if let Some(name) = switch_target(a) {
+                        bases.insert(name);
+                    }
+                })
+            });
+        // Прозрачные клавиши базового слоя Kanata может отдавать первому слою
+        let delegate = self
+            .config
+            .kanata
+            .options
+            .iter()
+            .any(|(k, v)| k == "delegate-to-first-layer" && v == "yes");
+        if delegate {
+            bases.extend(
+                self.layers
+                    .values()
+                    .min_by_key(|l| l.index)
+                    .map(|l| l.name.as_str()),
+            );
+        }
+        self.layers
+            .values()
+            .filter(|l| !bases.contains(l.name.as_str()))
+            .flat_map(|l| {
+                l.transparent.iter().filter_map(|k| {
+                    let action = l.keys.get(k)?;
+                    bases
+                        .iter()
+                        .all(|b| {
+                            self.layers
+                                .get(*b)
+                                .is_some_and(|b| b.keys.get(k) == Some(action))
+                        })
+                        .then_some((l.name.as_str(), *k))
+                })
+            })
+            .collect()
+    }
+}
+
+fn switch_target(action: &Action) -> Option<&str> {
+    match action {
+        Action::LayerSwitch(l)
+        | Action::LayerTo(l)
+        | Action::LayerToggle(l)
+        | Action::LayerTapToggle(l) => Some(l),
+        Action::LayerOff => Some("default"),
+        _ => None,
+    }
+}
+
+fn action_to_kanata(action: &Action, layout: &Layout) -> Result<String, String> {
     Ok(match action {
         Action::Tap(key) => key_to_kanata(key),
         Action::Transparent => "_".into(),
         Action::NoAction => "XX".into(),
         Action::Alias(a) => format!("@{}", a),
-        Action::TapHold(tap, hold) => format!(
-            "(tap-hold {} {} {} {})",
-            config::TAP_HOLD_MS,
-            config::TAP_HOLD_MS,
-            action_to_kanata(tap)?,
-            action_to_kanata(hold)?,
-        ),
+        Action::TapHold(tap, hold, cfg) => {
+            let timeout = cfg.timeout.unwrap_or(layout.config.tapping_term);
+            let (name, keys) = match &cfg.flavor {
+                TapHoldFlavor::Default => ("tap-hold", None),
+                TapHoldFlavor::HoldOnOtherKeyPress => ("tap-hold-press", None),
+                TapHoldFlavor::PermissiveHold => ("tap-hold-release", None),
+                TapHoldFlavor::ReleaseKeys(keys) | TapHoldFlavor::OppositeHand(keys) => {
+                    ("tap-hold-release-keys", Some(keys))
+                }
+                TapHoldFlavor::ExceptKeys(keys) => ("tap-hold-except-keys", Some(keys)),
+            };
+            let mut res = format!(
+                "({} {} {} {} {}",
+                name,
+                timeout,
+                timeout,
+                action_to_kanata(tap, layout)?,
+                action_to_kanata(hold, layout)?,
+            );
+            if let Some(keys) = keys {
+                res += &format!(
+                    " ({})",
+                    keys.iter().map(key_to_kanata).collect::<Vec<_>>().join(" ")
+                );
+            }
+            res + ")"
+        }
         Action::Multi(elems) => {
             let taps: Vec<_> = elems
                 .iter()
@@ -151,7 +312,7 @@ fn action_to_kanata(action: &Action) -> Result<String, String> {
             }
             let res = elems
                 .iter()
-                .map(action_to_kanata)
+                .map(|a| action_to_kanata(a, layout))
                 .collect::<Result<Vec<_>, _>>()?;
             let (actions, keys): (Vec<String>, Vec<String>) =
                 res.into_iter().partition(|s| s.starts_with("("));
@@ -159,24 +320,172 @@ fn action_to_kanata(action: &Action) -> Result<String, String> {
             format!("(multi {} {})", actions.join(" "), keys.join(" "))
         }
         Action::LayerWhileHeld(l) => format!("(layer-while-held {})", l),
-        Action::LayerSwitch(l) => format!("(layer-switch {})", l),
-        Action::Unicode(_) => todo!(),
-        Action::Sequence(actions) => format!(
-            "(macro {})",
-            actions
-                .iter()
-                .map(action_to_kanata)
-                .collect::<Result<Vec<_>, _>>()?
-                .join(" ")
+        Action::LayerSwitch(l) | Action::LayerTo(l) => format!("(layer-switch {})", l),
+        Action::LayerOneShot(l) => format!(
+            "(one-shot {} (layer-while-held {}))",
+            layout.config.one_shot_timeout, l
         ),
-        Action::Hold(_) | Action::Release(_) => {
+        Action::LayerToggle(_) | Action::LayerTapToggle(_) => {
+            return Err(format!(
+                "Layer toggle is not supported by Kanata: {:?}",
+                action
+            ));
+        }
+        Action::LayerOff => "(layer-switch default)".into(),
+        Action::LayerLock => return Err("Layer lock is not supported by Kanata".to_string()),
+        Action::Unicode(ch) => format!("(unicode {})", ch),
+        Action::Sequence(actions) => {
+            format!("(macro {})", sequence_to_kanata(actions, layout)?.join(" "))
+        }
+        Action::Hold(_) | Action::Release(_) | Action::Delay(_) => {
             return Err(format!("Action {:?} not in sequence", action));
         }
+        Action::MoveMouse(direction, interval, distance) => {
+            format!("(movemouse-{} {} {})", direction, interval, distance)
+        }
+        Action::MoveMouseAccel(direction, interval, accel, min, max) => format!(
+            "(movemouse-accel-{} {} {} {} {})",
+            direction, interval, accel, min, max
+        ),
+        Action::MouseSpeed(speed) => format!("(movemouse-speed {})", speed),
+        Action::VirtualKey(event, action, name) => format!(
+            "({} {}-vkey {})",
+            match event {
+                VirtualKeyEvent::OnPress => "on-press",
+                VirtualKeyEvent::OnRelease => "on-release",
+            },
+            match action {
+                VirtualKeyAction::Tap => "tap",
+                VirtualKeyAction::Press => "press",
+                VirtualKeyAction::Release => "release",
+                VirtualKeyAction::Toggle => "toggle",
+            },
+            name
+        ),
+        Action::Shifted(base, shifted) => {
+            let shifted_action = action_to_kanata(shifted, layout)?;
+            format!(
+                "(fork {} {} (lsft rsft))",
+                action_to_kanata(base, layout)?,
+                // Shift остается зажатым, одиночную клавишу нужно набрать без него
+                match shifted.as_ref() {
+                    Action::Tap(_) => format!("(unshift {})", shifted_action),
+                    _ => shifted_action,
+                }
+            )
+        }
+        Action::Switch(cases) => {
+            let cases = cases
+                .iter()
+                .map(|c| {
+                    Ok(format!(
+                        "({}) {} {}",
+                        c.conditions
+                            .iter()
+                            .map(condition_to_kanata)
+                            .collect::<Vec<_>>()
+                            .join(" "),
+                        action_to_kanata(&c.action, layout)?,
+                        if c.fallthrough {
+                            "fallthrough"
+                        } else {
+                            "break"
+                        }
+                    ))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            format!("(switch {})", cases.join(" "))
+        }
+        Action::Repeat => "rpt".into(),
+        Action::RepeatAny => "rpt-any".into(),
+        Action::AltRepeat => {
+            if layout.alt_repeat.is_empty() {
+                return Err(
+                    "Kanata has no alt-repeat, define pairs with defaltrepeat to emulate it"
+                        .to_string(),
+                );
+            }
+            warn!("Kanata has no alt-repeat, emulated with key-history for defaltrepeat pairs");
+            let cases = layout
+                .alt_repeat
+                .iter()
+                .map(|(key, action)| {
+                    Ok(format!(
+                        "((key-history {} 1)) {} break",
+                        key_to_kanata(key),
+                        action_to_kanata(action, layout)?
+                    ))
+                })
+                .collect::<Result<Vec<_>, String>>()?;
+            format!("(switch {} () XX break)", cases.join(" "))
+        }
     })
 }
 
+fn condition_to_kanata(condition: &SwitchCondition) -> String {
+    let list = |name: &str, items: &[SwitchCondition]| {
+        let items: Vec<_> = items.iter().map(condition_to_kanata).collect();
+        format!("({} {})", name, items.join(" "))
+    };
+    match condition {
+        SwitchCondition::Key(key) => key_to_kanata(key),
+        SwitchCondition::Layer(layer) => format!("(layer {})", layer),
+        SwitchCondition::History(key, n) => format!("(key-history {} {})", key_to_kanata(key), n),
+        SwitchCondition::And(items) => list("and", items),
+        SwitchCondition::Or(items) => list("or", items),
+        SwitchCondition::Not(item) => format!("(not {})", condition_to_kanata(item)),
+    }
+}
+
+fn mouse_key(direction: &str) -> String {
+    format!("(movemouse-accel-{} 16 1000 1 10)", direction)
+}
+
+// press/release модификатора превращается в аккорд вокруг шагов: S-(a b)
+fn sequence_to_kanata(actions: &[Action], layout: &Layout) -> Result<Vec<String>, String> {
+    let mut result = vec![];
+    let mut i = 0;
+    while i < actions.len() {
+        match &actions[i] {
+            Action::Hold(key) => {
+                let end = actions[i + 1..]
+                    .iter()
+                    .position(|a| matches!(a, Action::Release(k) if k == key))
+                    .ok_or(format!("Key {:?} is pressed but never released", key))?;
+                let mods = format_mods(&[key])
+                    .filter(|_| key.is_modifier())
+                    .ok_or(format!(
+                        "Kanata macros can only hold modifiers, found {:?}",
+                        key
+                    ))?;
+                let inner = sequence_to_kanata(&actions[i + 1..i + 1 + end], layout)?;
+                result.push(match inner.as_slice() {
+                    [chord] if chord.ends_with(")") && !chord.starts_with("(") => {
+                        format!("{}-{}", mods, chord)
+                    }
+                    _ => format!("{}-({})", mods, inner.join(" ")),
+                });
+                i += end + 2;
+                continue;
+            }
+            Action::Release(key) => {
+                return Err(format!("Key {:?} is released but never pressed", key));
+            }
+            Action::Delay(ms) => result.push(ms.to_string()),
+            a => result.push(action_to_kanata(a, layout)?),
+        }
+        i += 1;
+    }
+    Ok(result)
+}
+
 pub fn format_mods(mods: &[&Key]) -> Option<String> {
-    let set: HashSet<_> = mods.iter().copied().map(|k| k.clone()).collect();
+    let mut set: Vec<&Key> = Vec::with_capacity(mods.len());
+    mods.iter().for_each(|m| {
+        if !set.contains(m) {
+            set.push(m)
+        }
+    });
     let res = set
         .iter()
         .filter_map(|m| {
@@ -186,7 +495,7 @@ pub fn format_mods(mods: &[&Key]) -> Option<String> {
                 Key::LeftShift => "S",
                 Key::RightShift => "RS",
                 Key::LeftAlt => "A",
-                Key::RightAlt => "A",
+                Key::RightAlt => "RA",
                 Key::LeftMeta => "M",
                 Key::RightMeta => "RM",
                 _ => return None,
@@ -337,10 +646,10 @@ fn key_to_kanata(key: &Key) -> String {
         Key::KpEqual => "kp=".into(),
 
         Key::MediaPlayPause => "pp".into(),
-        Key::MouseCursorUp => todo!(),
-        Key::MouseCursorDown => todo!(),
-        Key::MouseCursorLeft => todo!(),
-        Key::MouseCursorRight => todo!(),
+        Key::MouseCursorUp => mouse_key("up"),
+        Key::MouseCursorDown => mouse_key("down"),
+        Key::MouseCursorLeft => mouse_key("left"),
+        Key::MouseCursorRight => mouse_key("right"),
         Key::MouseWheelUp => "mwu".into(),
         Key::MouseWheelDown => "mwd".into(),
         Key::MouseWheelLeft => "mwl".into(),
@@ -350,8 +659,246 @@ fn key_to_kanata(key: &Key) -> String {
         Key::MouseButton3 => "mmid".into(),
         Key::MouseButton4 => "mbck".into(),
         Key::MouseButton5 => "mfwd".into(),
-        Key::MouseAcceleration0 => todo!(),
-        Key::MouseAcceleration1 => todo!(),
-        Key::MouseAcceleration2 => todo!(),
+        Key::MouseAcceleration0 => "(movemouse-speed 25)".into(),
+        Key::MouseAcceleration1 => "(movemouse-speed 50)".into(),
+        Key::MouseAcceleration2 => "(movemouse-speed 200)".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::layout::Layout;
+    use s_expression::Expr::*;
+    use std::collections::{HashMap, HashSet};
+
+    fn kanata(layout: &str) -> Result<String, String> {
+        layout.parse::<Layout>()?.kanata()
+    }
+
+    fn norm(s: &str) -> String {
+        s_expression::from_str(s).unwrap().to_string()
+    }
+
+    fn forms(out: &str, head: &str) -> Vec<Vec<String>> {
+        let out = format!("({})", out);
+        let expr = s_expression::from_str(&out).unwrap();
+        expr.list()
+            .unwrap()
+            .iter()
+            .filter_map(|form| match form.list().ok()?.as_slice() {
+                [Atom(h), items @ ..] if *h == head => {
+                    Some(items.iter().map(|i| i.to_string()).collect())
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn layer(out: &str, name: &str) -> HashMap<String, String> {
+        let layer = forms(out, "deflayermap")
+            .into_iter()
+            .find(|f| f[0] == format!("({})", name))
+            .unwrap_or_else(|| panic!("Layer {} not found in {}", name, out));
+        layer[1..]
+            .chunks(2)
+            .map(|x| (x[0].clone(), x[1].clone()))
+            .collect()
+    }
+
+    fn body(keys: &[(&str, &str)]) -> HashMap<String, String> {
+        keys.iter().map(|(k, a)| (k.to_string(), norm(a))).collect()
+    }
+
+    #[test]
+    fn actions() {
+        #[rustfmt::skip]
+        let cases = [
+            ("(defsrc a)", r#"(macro "Hi" (delay 50) (press lsft) b c (release lsft))"#, "(macro S-h i 50 S-(b c))"),
+            ("(defsrc a)", "(macro (press lctl) (press lsft) t (release lsft) (release lctl))", "(macro C-S-(t))"),
+            ("(defsrc a)", ".é", "(unicode é)"),
+            ("(defcfg unicode-input windows) (defsrc a)", ".é", "(macro A-(kp0 kp2 kp3 kp3))"),
+            ("(defunicode en ´ (dead RA-e) é (compose ´ e) € RA-5) (defsrc a)", ".é", "(macro RA-e e)"),
+            ("(defunicode en ´ (dead RA-e) € RA-5) (defsrc a)", ".€", "RA-5"),
+            ("(defunicode en ´ (dead RA-e)) (defsrc a)", ".´", "(macro RA-e spc)"),
+            ("(defsrc a)", "(movemouse up 10 2)", "(movemouse-up 10 2)"),
+            ("(defsrc a)", "(movemouse-accel left 4 1000 1 5)", "(movemouse-accel-left 4 1000 1 5)"),
+            ("(defsrc a)", "(movemouse-speed 50)", "(movemouse-speed 50)"),
+            ("(defsrc a)", "mcdn", "(movemouse-accel-down 16 1000 1 10)"),
+            ("(defsrc a)", "rpt", "rpt"),
+            ("(defsrc a)", "(repeat any)", "rpt-any"),
+            ("(defaltrepeat n p p n) (defsrc a)", "arpt", "(switch ((key-history n 1)) p break ((key-history p 1)) n break () XX break)"),
+            ("(defsrc a s j) (defhands left (a s) right (j))", "(tap-hold a lsft :flavor opposite-hand)", "(tap-hold-release-keys 200 200 a lsft (s))"),
+            ("(defsrc a s j)", "(tap-hold j rsft :flavor except-keys :keys (s))", "(tap-hold-except-keys 200 200 j rsft (s))"),
+            ("(defvirtualkeys sft lsft) (defsrc a)", "(on-press toggle sft)", "(on-press toggle-vkey sft)"),
+            ("(defvirtualkeys sft lsft) (defsrc a)", "(on-release tap sft)", "(on-release tap-vkey sft)"),
+            ("(defsrc a)", "(switch ((and lsft lctl)) 2 break (lalt (layer default)) 3 fallthrough () a break)", "(switch ((and lsft lctl)) 2 break (lalt (layer default)) 3 fallthrough () a break)"),
+            ("(defsrc a)", "(switch ((key-history a 1) (not rsft)) rpt break)", "(switch ((key-history a 1) (not rsft)) rpt break)"),
+            ("(defsrc a)", "(shifted , ;)", "(fork , (unshift ;) (lsft rsft))"),
+            ("(defsrc a)", "(shifted 1 S-2)", "(fork 1 S-2 (lsft rsft))"),
+        ];
+        for (prelude, action, expected) in cases {
+            let layout = format!("{} (deflayermap default a {})", prelude, action);
+            let out = kanata(&layout).unwrap_or_else(|e| panic!("{}: {}", layout, e));
+            assert_eq!(
+                layer(&out, "default").get("a"),
+                Some(&norm(expected)),
+                "{}",
+                layout
+            );
+        }
+    }
+
+    #[test]
+    fn errors() {
+        #[rustfmt::skip]
+        let cases = [
+            "(defcfg unicode-input error) (defsrc a) (deflayermap default a .é)",
+            "(defcfg unicode-input windows) (defsrc a) (deflayermap default a .€)",
+            "(defsrc a) (deflayermap default a arpt)",
+            "(defsrc a) (deflayermap default a (on-press tap nope))",
+            "(defsrc a) (deflayermap default a (switch (lsft) 2 stop))",
+            "(defsrc a) (deflayermap default a (switch ((key-history a 9)) 2 break))",
+            "(defsrc a) (defoverride default A-a b :one-mod maybe)",
+            "(defsrc a) (defoverride default A-a b :negative-mods (a))",
+            "(defsrc a) (defoverride default A-a b :activation (never))",
+            "(defsrc a) (deflayermap default a (layer-toggle nav)) (deflayermap nav)",
+            "(defsrc a) (deflayermap default a (layer-tap-toggle nav)) (deflayermap nav)",
+            "(defsrc a (spc :label lthumb) (spc :label rthumb)) (deflayermap nav lthumb tab)",
+            "(defsrc a (spc :label lthumb) (spc :label rthumb)) (defoverride default S-rthumb a)",
+        ];
+        for layout in cases {
+            assert!(kanata(layout).is_err(), "{}", layout);
+        }
+    }
+
+    #[test]
+    fn mixins() {
+        let out = kanata(
+            "(defsrc a b c)
+            (deflayer default a b c)
+            (deflayermap mouse c m1)
+            (deflayer (base default) q _ _)
+            (deflayer (gaming base mouse) _ _ _)",
+        )
+        .unwrap();
+        assert_eq!(layer(&out, "gaming"), body(&[("a", "q"), ("c", "mlft")]));
+    }
+
+    #[test]
+    fn groups() {
+        let out = kanata(
+            "(defsrc a b c d)
+            (defgroup left (a b))
+            (deflayermap nav (group left lsft) a lctl (rest X))",
+        )
+        .unwrap();
+        assert_eq!(
+            layer(&out, "nav"),
+            body(&[("a", "lctl"), ("b", "lsft"), ("c", "XX"), ("d", "XX")])
+        );
+    }
+
+    #[test]
+    fn virtual_keys() {
+        let out = kanata("(defvirtualkeys sft lsft) (defsrc a)").unwrap();
+        assert_eq!(forms(&out, "defvirtualkeys"), vec![vec!["sft", "lsft"]]);
+    }
+
+    #[test]
+    fn override_options() {
+        let out = kanata(
+            "(defsrc a b)
+            (deflayermap default)
+            (defoverride default
+                A-a b :negative-mods (lctl)
+                S-C-b a :one-mod yes)",
+        )
+        .unwrap();
+        let overrides: HashSet<_> = forms(&out, "defoverridesv2")[0]
+            .chunks(4)
+            .map(|x| x.join(" "))
+            .collect();
+        let expected = [
+            "(lalt a) (b) (lctl) ()",
+            "(lsft b) (a) () ()",
+            "(lctl b) (a) () ()",
+        ];
+        assert_eq!(overrides, HashSet::from(expected.map(String::from)));
+    }
+
+    #[test]
+    fn runtime_transparency() {
+        let layout = "(defsrc a b c)
+            (deflayermap default a x b (layer-switch game) c (layer-while-held nav))
+            (deflayermap game a z)
+            (deflayermap nav)";
+
+        let out = kanata(layout).unwrap();
+        assert_eq!(
+            layer(&out, "nav"),
+            body(&[
+                ("a", "x"),
+                ("b", "(layer-switch game)"),
+                ("c", "(layer-while-held nav)")
+            ])
+        );
+
+        let out = kanata(&format!("(defcfg transparency runtime) {}", layout)).unwrap();
+        assert_eq!(
+            layer(&out, "nav"),
+            body(&[("a", "x"), ("b", "_"), ("c", "_")])
+        );
+        assert_eq!(
+            layer(&out, "game"),
+            body(&[
+                ("a", "z"),
+                ("b", "(layer-switch game)"),
+                ("c", "(layer-while-held nav)")
+            ])
+        );
+    }
+
+    #[test]
+    fn runtime_bases() {
+        let layout = "(defcfg transparency runtime)
+            (defvirtualkeys vk (layer-to game))
+            (defaltrepeat a (layer-switch sym))
+            (defsrc a b c)
+            (deflayermap default a x c (layer-while-held nav))
+            (deflayermap game a z)
+            (deflayermap sym b y)
+            (deflayermap nav)";
+        let out = kanata(layout).unwrap();
+        assert_eq!(layer(&out, "nav"), body(&[("a", "x"), ("c", "_")]));
+
+        let layout = "(defcfg transparency runtime (kanata delegate-to-first-layer yes))
+            (defsrc a b)
+            (deflayermap base a y)
+            (deflayermap default a x b (layer-while-held nav))
+            (deflayermap nav)";
+        let out = kanata(layout).unwrap();
+        assert_eq!(layer(&out, "nav"), body(&[("a", "x"), ("b", "_")]));
+
+        for action in ["(layer-toggle game)", "(layer-tap-toggle game)"] {
+            let layout = format!(
+                "(defcfg transparency runtime) (defsrc a b)
+                (deflayermap default a x b {}) (deflayermap game a z) (deflayermap nav)",
+                action
+            );
+            assert!(kanata(&layout).is_err(), "{}", layout);
+        }
+    }
+
+    #[test]
+    fn labels() {
+        let out =
+            kanata("(defsrc a (spc :label lthumb) (spc :label rthumb)) (deflayermap default a b)");
+        assert_eq!(forms(&out.unwrap(), "defsrc"), vec![vec!["a", "spc"]]);
+
+        let out = kanata("(defsrc a (ent :label thumb)) (defoverride default S-thumb a)").unwrap();
+        assert_eq!(
+            forms(&out, "defoverridesv2"),
+            vec![vec!["(lsft enter)", "(a)", "()", "()"]]
+        );
     }
 }
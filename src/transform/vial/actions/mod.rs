@@ -1,6 +1,5 @@
 mod macros;
 mod tapdance;
-use super::super::config;
 pub use super::keycode::Keycode;
 pub use macros::{Macro, MacroAction};
 pub use tapdance::TapDance;
@@ -23,13 +22,13 @@ impl std::fmt::Debug for VialAction {
 }
 
 impl VialAction {
-    pub fn tap_hold(tap: Keycode, hold: Keycode) -> Self {
+    pub fn tap_hold(tap: Keycode, hold: Keycode, tapping_term: u16) -> Self {
         Self::TapDance(TapDance {
             tap: tap,
             hold: hold,
             double_tap: Keycode(0),
             tap_hold: Keycode(0),
-            tapping_term: config::TAP_HOLD_MS as u16,
+            tapping_term,
         })
     }
 }
@@ -7,13 +7,19 @@ use log::warn;
 use overrides::Override;
 
 use super::graph::{Node, priority_topo_sort};
-use crate::layout::{Action, Layer, Layout};
+use crate::layout::{
+    Action, Config, Layer, Layout, OverrideOptions, SwitchCase, SwitchCondition, TapHoldFlavor,
+    Transparency,
+};
 use actions::{Macro, MacroAction, TapDance, VialAction};
 use device::{get_device, unlock_device};
 use hidapi::HidApi;
 use keys::keys::{Key, KeyIndex};
 use parser::VialItem;
-use std::{collections::HashMap, ops::Deref};
+use std::{
+    collections::{HashMap, HashSet},
+    ops::Deref,
+};
 use vitaly::protocol;
 impl Layout {
     fn sorted_layers(&self) -> Result<Vec<&Layer>, String> {
@@ -44,6 +50,12 @@ impl Layout {
             .vial
             .ok_or("Vial is not defined".to_string())?;
         let sorted = self.sorted_layers()?;
+        if !self.virtual_keys.is_empty() {
+            warn!("Virtual keys are not supported by Vial, defvirtualkeys ignored");
+        }
+        if !self.alt_repeat.is_empty() {
+            warn!("Alt-repeat pairs are defined by the firmware in Vial, defaltrepeat ignored");
+        }
         let api = HidApi::new().map_err(|e| e.to_string())?;
 
         let layers_by_name: HashMap<&str, usize> = sorted
@@ -57,23 +69,40 @@ impl Layout {
             macros: Default::default(),
             tap_dances: Default::default(),
             overrides: Default::default(),
-            version: 6,
+            version: self.config.vial.version,
+            tapping_term: self.config.tapping_term,
+            firmware_tap_hold: false,
         };
 
+        let runtime = runtime_transparent(&sorted, self.config.transparency);
         let mut layers: Vec<_> = sorted
             .iter()
-            .map(|layer| {
+            .zip(&runtime)
+            .map(|(layer, runtime)| {
+                let layer_index = layers_by_name
+                    .get(layer.name.as_str())
+                    .ok_or(format!("Layer {:?} not found", layer.name))?;
                 let keys: HashMap<&KeyIndex, Keycode> = layer
                     .keys
                     .iter()
                     .map(|(key_index, action)| {
-                        let action = vial.action_to_keycode(action)?;
+                        if runtime.contains(key_index) {
+                            return Ok((key_index, Keycode(1)));
+                        }
+                        let action = match action {
+                            Action::Switch(cases) => {
+                                let fallback = vial.lower_switch(*layer_index, cases)?;
+                                vial.action_to_keycode(&fallback)?
+                            }
+                            Action::Shifted(base, shifted) => {
+                                vial.lower_shifted(*layer_index, base, shifted)?;
+                                vial.action_to_keycode(base)?
+                            }
+                            _ => vial.action_to_keycode(action)?,
+                        };
                         Ok::<_, String>((key_index, action))
                     })
                     .collect::<Result<_, _>>()?;
-                let layer_index = layers_by_name
-                    .get(layer.name.as_str())
-                    .ok_or(format!("Layer {:?} not found", layer.name))?;
 
                 layer.overrides.iter().for_each(|o| {
                     _ = vial
@@ -85,6 +114,12 @@ impl Layout {
             .collect::<Result<_, _>>()?;
 
         layers.sort_by_key(|(n, _)| *n);
+        if vial.firmware_tap_hold && self.config.tapping_term != Config::default().tapping_term {
+            warn!(
+                "Mod-tap and layer-tap keys use the firmware tapping term in Vial, \
+                defcfg tapping-term applies only to tap dances"
+            );
+        }
 
         let Some((device, capabilities, meta)) = get_device(&api, device_id) else {
             return Err("Device not found".to_string());
@@ -184,12 +219,42 @@ impl Layout {
     }
 }
 
+fn runtime_transparent(sorted: &[&Layer], transparency: Transparency) -> Vec<HashSet<KeyIndex>> {
+    let mut result: Vec<HashSet<KeyIndex>> = vec![];
+    for (i, layer) in sorted.iter().enumerate() {
+        let keys = match transparency {
+            Transparency::Flatten => HashSet::new(),
+            Transparency::Runtime if i == 0 => HashSet::new(),
+            Transparency::Runtime => layer
+                .transparent
+                .iter()
+                .filter(|k| {
+                    let action = layer.keys.get(k);
+                    // switch и shifted становятся overrides, а у них маска слоев своя
+                    if let Some(Action::Switch(_) | Action::Shifted(..)) = action {
+                        return false;
+                    }
+                    sorted[..i]
+                        .iter()
+                        .zip(&result)
+                        .all(|(lower, trns)| trns.contains(k) || lower.keys.get(k) == action)
+                })
+                .copied()
+                .collect(),
+        };
+        result.push(keys);
+    }
+    result
+}
+
 struct Vial<'a> {
     macros: HashMap<Macro, u8>,
     tap_dances: HashMap<TapDance, u8>,
     layers: &'a HashMap<&'a str, usize>,
     overrides: HashMap<Override, u16>,
     version: u32,
+    tapping_term: u16,
+    firmware_tap_hold: bool,
 }
 impl<'a> Vial<'a> {
     fn layer_by_name(&self, name: &str) -> Option<usize> {
@@ -203,7 +268,7 @@ impl<'a> Vial<'a> {
         let (target, target_mods): (Keycode, Vec<Key>) = match &o.action {
             Action::Tap(key) => (Keycode::from_key(key, self.version)?, vec![]),
             Action::NoAction => (Keycode(0), vec![]),
-            Action::TapHold(action, _) => match action.deref() {
+            Action::TapHold(action, _, _) => match action.deref() {
                 Action::Tap(key) => (Keycode::from_key(key, self.version)?, vec![]),
                 _ => {
                     return Err(format!(
@@ -249,20 +314,136 @@ impl<'a> Vial<'a> {
             target: target,
             source_mods: o.mods.clone(),
             target_mods: target_mods,
+            options: o.options.clone(),
         };
         let entry = self.overrides.entry(o).or_insert(0);
         *entry |= 1 << layer;
         Ok(())
     }
 
+    fn lower_switch(&mut self, layer: usize, cases: &[SwitchCase]) -> Result<Action, String> {
+        let err = || {
+            format!(
+                "Switch {:?} is not supported by Vial, only cases on held modifiers \
+                with break and a last () case with a key can become key overrides",
+                cases
+            )
+        };
+        let Some((
+            SwitchCase {
+                conditions: fallback_conditions,
+                action: fallback,
+                fallthrough: false,
+            },
+            cases,
+        )) = cases.split_last()
+        else {
+            return Err(err());
+        };
+        let Action::Tap(key) = fallback else {
+            return Err(err());
+        };
+        if !fallback_conditions.is_empty() {
+            return Err(err());
+        }
+        for case in cases {
+            if case.fallthrough || case.conditions.is_empty() {
+                return Err(err());
+            }
+            for condition in &case.conditions {
+                let mods = match condition {
+                    SwitchCondition::Key(k) => vec![*k],
+                    SwitchCondition::And(items) => items
+                        .iter()
+                        .map(|c| match c {
+                            SwitchCondition::Key(k) => Some(*k),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<_>>>()
+                        .ok_or_else(err)?,
+                    _ => return Err(err()),
+                };
+                if !mods.iter().all(|m| m.is_modifier()) {
+                    return Err(err());
+                }
+                // В Kanata модификаторы условия остаются зажатыми
+                let mut taps: Vec<_> = mods.iter().map(|m| Action::Tap(*m)).collect();
+                match &case.action {
+                    Action::Tap(_) => taps.push(case.action.clone()),
+                    Action::Multi(actions) => actions.iter().for_each(|a| {
+                        if !taps.contains(a) {
+                            taps.push(a.clone());
+                        }
+                    }),
+                    _ => return Err(err()),
+                }
+                self.add_override(
+                    layer,
+                    &crate::layout::Override {
+                        key: *key,
+                        mods,
+                        action: Action::Multi(taps),
+                        options: Default::default(),
+                    },
+                )?;
+            }
+        }
+        Ok(fallback.clone())
+    }
+
+    fn lower_shifted(
+        &mut self,
+        layer: usize,
+        base: &Action,
+        shifted: &Action,
+    ) -> Result<(), String> {
+        let Action::Tap(key) = base else {
+            return Err(format!(
+                "Shifted action is supported by Vial only on a key, found {:?}",
+                base
+            ));
+        };
+        self.add_override(
+            layer,
+            &crate::layout::Override {
+                key: *key,
+                mods: vec![Key::LeftShift, Key::RightShift],
+                action: shifted.clone(),
+                options: OverrideOptions {
+                    one_mod: true,
+                    ..Default::default()
+                },
+            },
+        )
+    }
+
     pub fn action_to_vial(&mut self, action: &Action) -> Result<VialAction, String> {
         Ok(VialAction::Keycode(match action {
             Action::NoAction => Keycode(0),
             Action::Tap(k) => Keycode::from_key(k, self.version)?,
-            Action::TapHold(tap, hold) => {
+            Action::TapHold(tap, hold, cfg) => {
+                if let TapHoldFlavor::OppositeHand(_) = cfg.flavor {
+                    warn!(
+                        "Opposite-hand tap-hold is a firmware option in Vial, \
+                        enable Chordal Hold in QMK, ignored"
+                    );
+                } else if cfg.flavor != TapHoldFlavor::Default {
+                    warn!(
+                        "Flavor {:?} is not supported per key in Vial, ignored",
+                        cfg.flavor
+                    );
+                }
+                if let Some(timeout) = cfg.timeout {
+                    return Ok(VialAction::tap_hold(
+                        self.action_to_keycode(tap)?,
+                        self.action_to_keycode(hold)?,
+                        timeout,
+                    ));
+                }
                 if let Action::Tap(tap) = tap.as_ref() {
                     match hold.as_ref() {
                         Action::Tap(k) if k.is_modifier() => {
+                            self.firmware_tap_hold = true;
                             return Keycode::from_name(
                                 format!(
                                     "{}_T({})",
@@ -277,6 +458,7 @@ impl<'a> Vial<'a> {
                             let l = self
                                 .layer_by_name(x)
                                 .ok_or(format!("Layer {} not found", x))?;
+                            self.firmware_tap_hold = true;
                             return Keycode::from_name(
                                 format!("LT({},{})", l, key_to_string(tap)),
                                 self.version,
@@ -292,6 +474,7 @@ impl<'a> Vial<'a> {
                                 })
                                 .collect();
                             if mods.len() == actions.len() {
+                                self.firmware_tap_hold = true;
                                 return Keycode::from_name(
                                     format!("MT({},{})", mods.join("|"), key_to_string(tap)),
                                     self.version,
@@ -306,23 +489,84 @@ impl<'a> Vial<'a> {
                 return Ok(VialAction::tap_hold(
                     self.action_to_keycode(tap)?,
                     self.action_to_keycode(hold)?,
+                    self.tapping_term,
                 ));
             }
-            Action::Alias(_) | Action::Unicode(_) => {
+            Action::Alias(_) => {
                 return Err(format!("Action {:?} not implemented", action));
             }
-            Action::LayerSwitch(x) => {
+            Action::Unicode(ch) => {
+                return Err(format!(
+                    "Character {:?} is not in the keymap tables, \
+                    set (defcfg unicode-input linux) or (defcfg unicode-input windows) to type it",
+                    ch
+                ));
+            }
+            Action::LayerSwitch(x)
+            | Action::LayerWhileHeld(x)
+            | Action::LayerToggle(x)
+            | Action::LayerTo(x)
+            | Action::LayerOneShot(x)
+            | Action::LayerTapToggle(x) => {
                 let layer = self
                     .layer_by_name(x)
                     .ok_or(format!("Layer {} not found", x))?;
-                Keycode::from_name(format!("DF({})", layer), self.version)?
+                let function = match action {
+                    Action::LayerSwitch(_) => "DF",
+                    Action::LayerWhileHeld(_) => "MO",
+                    Action::LayerToggle(_) => "TG",
+                    Action::LayerTo(_) => "TO",
+                    Action::LayerOneShot(_) => "OSL",
+                    _ => "TT",
+                };
+                Keycode::from_name(format!("{}({})", function, layer), self.version)?
             }
-            Action::LayerWhileHeld(x) => {
+            Action::LayerOff => {
                 let layer = self
-                    .layer_by_name(x)
-                    .ok_or(format!("Layer {} not found", x))?;
-
-                Keycode::from_name(format!("MO({})", layer), self.version)?
+                    .layer_by_name("default")
+                    .ok_or("Layer default not found".to_string())?;
+                Keycode::from_name(format!("TO({})", layer), self.version)?
+            }
+            Action::LayerLock => Keycode::from_name("QK_LAYER_LOCK".to_string(), self.version)?,
+            Action::MoveMouse(direction, ..) | Action::MoveMouseAccel(direction, ..) => {
+                warn!(
+                    "Mouse speed is set in the firmware, parameters of {:?} ignored",
+                    action
+                );
+                Keycode::from_key(&direction.key(), self.version)?
+            }
+            Action::VirtualKey(_, _, name) => {
+                return Err(format!(
+                    "Virtual keys are not supported by Vial, {} cannot be used",
+                    name
+                ));
+            }
+            Action::Shifted(..) => {
+                return Err(format!(
+                    "Shifted action {:?} is supported by Vial only as a key action",
+                    action
+                ));
+            }
+            Action::Switch(_) => {
+                return Err(format!(
+                    "Switch {:?} is supported by Vial only as a key action",
+                    action
+                ));
+            }
+            Action::Repeat => Keycode::from_name("QK_REPEAT_KEY".to_string(), self.version)?,
+            Action::RepeatAny => {
+                warn!("Vial repeats only the last key, (repeat any) used as (repeat)");
+                Keycode::from_name("QK_REPEAT_KEY".to_string(), self.version)?
+            }
+            Action::AltRepeat => Keycode::from_name("QK_ALT_REPEAT_KEY".to_string(), self.version)?,
+            Action::MouseSpeed(speed) => {
+                let key = match speed {
+                    0..=25 => Key::MouseAcceleration0,
+                    26..=100 => Key::MouseAcceleration1,
+                    _ => Key::MouseAcceleration2,
+                };
+                warn!("Mouse speed {}% approximated with {:?}", speed, key);
+                Keycode::from_key(&key, self.version)?
             }
             Action::Multi(elems) => {
                 let taps: Vec<_> = elems
@@ -367,6 +611,7 @@ impl<'a> Vial<'a> {
                             Action::Release(key) => {
                                 MacroAction::Up(Keycode::from_key(key, self.version)?)
                             }
+                            Action::Delay(ms) => MacroAction::Delay(*ms),
                             a => MacroAction::Tap(self.action_to_keycode(a)?),
                         })
                     })
@@ -385,7 +630,7 @@ impl<'a> Vial<'a> {
                 });
                 return Ok(VialAction::Macro(Macro(result)));
             }
-            Action::Hold(_) | Action::Release(_) => {
+            Action::Hold(_) | Action::Release(_) | Action::Delay(_) => {
                 return Err(format!("Action {:?} not in sequence", action));
             }
         }))
@@ -422,3 +667,27 @@ impl<'a> Vial<'a> {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_keeps_overrides() {
+        let layout: Layout = "(defcfg transparency runtime) (defsrc a b)
+            (deflayermap default a (shifted a b) b c)
+            (deflayermap nav)"
+            .parse()
+            .unwrap();
+        let sorted = layout.sorted_layers().unwrap();
+        let runtime = runtime_transparent(&sorted, layout.config.transparency);
+        let nav = sorted.iter().position(|l| l.name == "nav").unwrap();
+        let plain: HashSet<_> = sorted[nav]
+            .keys
+            .iter()
+            .filter(|(_, a)| **a == Action::Tap(Key::C))
+            .map(|(k, _)| *k)
+            .collect();
+        assert_eq!(runtime[nav], plain);
+    }
+}
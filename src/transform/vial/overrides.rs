@@ -2,6 +2,7 @@ use keys::keys::Key;
 use std::collections::HashSet;
 use vitaly::protocol::KeyOverride;
 
+use crate::layout::OverrideOptions;
 use crate::transform::vial::keycode::key_to_mod;
 
 use super::keycode::Keycode;
@@ -12,11 +13,14 @@ pub struct Override {
     pub target: Keycode,
     pub source_mods: Vec<Key>,
     pub target_mods: Vec<Key>,
+    pub options: OverrideOptions,
 }
 
 impl Override {
     pub fn to_key_override(&self, layers_mask: u16, i: usize) -> Result<KeyOverride, String> {
         let (source, target) = self.get_mods();
+        let options = &self.options;
+        let suppressed = options.suppressed_mods.as_ref().unwrap_or(&target);
         Ok(KeyOverride {
             index: i as u8,
             ko_enabled: true,
@@ -24,14 +28,14 @@ impl Override {
             replacement: self.target.0,
             layers: layers_mask,
             trigger_mods: mods_to_mask(&source)?,
-            negative_mod_mask: 0,
-            suppressed_mods: mods_to_mask(&target)?,
-            ko_option_activation_trigger_down: true,
-            ko_option_activation_required_mod_down: true,
-            ko_option_activation_negative_mod_up: true,
-            ko_option_one_mod: false,
-            ko_option_no_reregister_trigger: false,
-            ko_option_no_unregister_on_other_key_down: false,
+            negative_mod_mask: mods_to_mask(&options.negative_mods)?,
+            suppressed_mods: mods_to_mask(suppressed)?,
+            ko_option_activation_trigger_down: options.activation_trigger_down,
+            ko_option_activation_required_mod_down: options.activation_required_mod_down,
+            ko_option_activation_negative_mod_up: options.activation_negative_mod_up,
+            ko_option_one_mod: options.one_mod,
+            ko_option_no_reregister_trigger: options.no_reregister_trigger,
+            ko_option_no_unregister_on_other_key_down: options.no_unregister_on_other_key_down,
         })
     }
     fn get_mods(&self) -> (Vec<Key>, Vec<Key>) {
@@ -89,6 +93,7 @@ mod tests {
             source_mods: src_mods.to_vec(),
             target_mods: dst_mods.to_vec(),
             target: Keycode::from_key(dst, 6)?,
+            options: Default::default(),
         })
     }
 